@@ -0,0 +1,686 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use clap::Parser;
+
+use erosion_simulation_lib::chunked::ChunkedSimulator;
+use erosion_simulation_lib::io::{self, HeightScale};
+use erosion_simulation_lib::mesh::{MeshOptions, TriangleMesh};
+use erosion_simulation_lib::params::{Boundary, ErosionModel, ErosionParams, PRESETS};
+use erosion_simulation_lib::simulator::ErosionSimulator;
+use erosion_simulation_lib::surface::SurfaceMaps;
+
+/// Erode a heightmap without opening Godot.
+///
+/// Reads an EXR heightmap (like `terrain_texture.exr`), runs the erosion
+/// simulation for a fixed number of iterations and writes the result as an EXR.
+/// Parameters start from a preset, a parameter file or the same defaults as
+/// `TerrainMesh`, and any given on the command line override those.
+///
+/// Heightmaps ending in `.f32` are read and written as raw little-endian floats
+/// instead, which is the only format `--chunk-size` streams from and to disk.
+/// Inputs can also be 16-bit greyscale `.png`s, `.r16`/`.raw` files from World
+/// Machine or Unity, or ESRI ASCII grids (`.asc`).
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The heightmap to erode - raw `.f32` files need `--width` and `--height`, which
+    /// `.r16`/`.raw` files only need if they aren't square.
+    input: PathBuf,
+    /// Where to write the eroded heightmap.
+    output: PathBuf,
+    /// The width of a raw `.f32`, `.r16` or `.raw` input, which has no header to read it from.
+    #[arg(long, requires = "height")]
+    width: Option<usize>,
+    /// The height of a raw `.f32`, `.r16` or `.raw` input.
+    #[arg(long, requires = "width")]
+    height: Option<usize>,
+    /// Erode the map in chunks this many cells across, streaming it from and to disk, for
+    /// maps too big to fit in memory. The input and output have to be raw `.f32` files.
+    #[arg(long, conflicts_with_all = [
+        "sediment_output", "flow_output", "layers_output", "mesh_output", "normal_output",
+        "surface_output", "height_scale", "normalize", "hardness", "hardness_channel",
+        "precipitation", "precipitation_channel",
+    ])]
+    chunk_size: Option<usize>,
+    /// Multiply every height in the input by this once it's read.
+    #[arg(long, conflicts_with = "normalize")]
+    height_scale: Option<f32>,
+    /// Stretch the input's heights so the lowest is MIN and the highest MAX once it's read.
+    #[arg(long, num_args = 2, value_names = ["MIN", "MAX"], allow_hyphen_values = true)]
+    normalize: Option<Vec<f32>>,
+    /// How many iterations to run.
+    #[arg(short, long, default_value_t = 100)]
+    iterations: usize,
+    /// Start from one of the built-in presets ("gentle hills", "badlands", "alpine").
+    #[arg(long, conflicts_with = "params")]
+    preset: Option<String>,
+    /// Start from the parameters in a `.toml` or `.json` file.
+    #[arg(long)]
+    params: Option<PathBuf>,
+    /// Save the final parameters to a `.toml` or `.json` file.
+    #[arg(long)]
+    save_params: Option<PathBuf>,
+    /// Write how much material was removed from and laid down on every cell to this EXR,
+    /// in its `erosion` and `deposition` channels.
+    #[arg(long)]
+    sediment_output: Option<PathBuf>,
+    /// Write how much water flows through every cell to the `flow` channel of this EXR.
+    #[arg(long)]
+    flow_output: Option<PathBuf>,
+    /// Write the material layers to this EXR - they're set up with `layers` in the `--params` file.
+    #[arg(long)]
+    layers_output: Option<PathBuf>,
+    /// Write a tangent-space normal map of the eroded terrain to this `.png` (16-bit) or EXR.
+    #[arg(long)]
+    normal_output: Option<PathBuf>,
+    /// How much to exaggerate the normal map's slopes.
+    #[arg(long, default_value_t = 1.0)]
+    normal_strength: f32,
+    /// Write how steep and how convex every cell of the eroded terrain is to this EXR, in its
+    /// `slope` and `curvature` channels.
+    #[arg(long)]
+    surface_output: Option<PathBuf>,
+    /// Write the eroded terrain as a triangle mesh with normals and UVs to this `.obj`, `.gltf`
+    /// or `.glb` file.
+    #[arg(long)]
+    mesh_output: Option<PathBuf>,
+    /// The horizontal distance between neighbouring cells in the mesh.
+    #[arg(long, default_value_t = 1.0)]
+    mesh_spacing: f32,
+    /// Multiply every height in the mesh by this.
+    #[arg(long, default_value_t = 1.0)]
+    mesh_height_scale: f32,
+    /// Only give every this many cells a vertex in the mesh, to decimate big maps.
+    #[arg(long, default_value_t = 1)]
+    mesh_step: usize,
+    /// Which erosion model to run ("droplet" or "shallow_water").
+    #[arg(long)]
+    model: Option<ErosionModel>,
+    /// How strongly slopes speed raindrops up.
+    #[arg(long)]
+    gravity: Option<f32>,
+    /// Carrying capacity of a raindrop - how much sediment it can carry.
+    #[arg(long)]
+    capacity: Option<f32>,
+    /// How much of its previous direction a raindrop keeps each step.
+    #[arg(long)]
+    inertia: Option<f32>,
+    /// How quickly raindrops pick up sediment.
+    #[arg(long)]
+    erosion_factor: Option<f32>,
+    /// How quickly raindrops drop excess sediment.
+    #[arg(long)]
+    deposition_factor: Option<f32>,
+    /// The diameter of a raindrop - how much area it covers.
+    #[arg(long)]
+    diameter: Option<f32>,
+    /// The maximum number of steps a raindrop takes.
+    #[arg(long)]
+    lifetime: Option<u32>,
+    /// How much water a raindrop starts with.
+    #[arg(long)]
+    starting_mass: Option<f32>,
+    /// The fraction of a raindrop's water that evaporates each step.
+    #[arg(long)]
+    evaporation: Option<f32>,
+    /// Raindrops slower than this die.
+    #[arg(long)]
+    min_velocity: Option<f32>,
+    /// What happens to raindrops that reach the edge of the map ("deposit", "discard",
+    /// "reflect", "clamp" or "wrap").
+    #[arg(long)]
+    boundary: Option<Boundary>,
+    /// How many raindrops to simulate each iteration - use 0 with `--thermal-interval 1` for
+    /// thermal weathering on its own.
+    #[arg(long)]
+    drops: Option<usize>,
+    /// Simulate raindrops tile by tile so each sees the changes made by the ones before it -
+    /// `--tiled` or `--tiled true` turns it on, and `--tiled false` turns it off.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    tiled: Option<bool>,
+    /// How many coarser, downsampled copies of the heightmap to erode before the full-resolution
+    /// iterations, carrying what they carve down to it.
+    #[arg(long)]
+    pyramid_levels: Option<u32>,
+    /// How many iterations to run on each of the coarser copies.
+    #[arg(long)]
+    pyramid_iterations: Option<u32>,
+    /// The steepest slope thermal weathering leaves alone, as the height difference per cell.
+    #[arg(long)]
+    thermal_talus: Option<f32>,
+    /// The fraction of the material over the talus moved by each thermal pass.
+    #[arg(long)]
+    thermal_rate: Option<f32>,
+    /// Run a thermal pass after every this many iterations - 0 turns them off.
+    #[arg(long)]
+    thermal_interval: Option<u32>,
+    /// A rock hardness map the same size as the input, in any format the input can be, from 0
+    /// (soft) to 1 (can't be eroded).
+    #[arg(long, conflicts_with = "hardness_channel")]
+    hardness: Option<PathBuf>,
+    /// Read the rock hardness map from this channel of the input (e.g. "G").
+    #[arg(long)]
+    hardness_channel: Option<String>,
+    /// How quickly rock hardens as the material above it is eroded away, and softens
+    /// under fresh sediment - 0 keeps the hardness map as it was loaded.
+    #[arg(long)]
+    hardening: Option<f32>,
+    /// A precipitation map the same size as the input, in any format the input can be - cells
+    /// get rain in proportion to it.
+    #[arg(long, conflicts_with = "precipitation_channel")]
+    precipitation: Option<PathBuf>,
+    /// Read the precipitation map from this channel of the input (e.g. "B").
+    #[arg(long)]
+    precipitation_channel: Option<String>,
+    /// How much more rain the highest point gets than the lowest - 1 doubles it.
+    #[arg(long)]
+    rain_elevation: Option<f32>,
+    /// How much more rain the steepest slope facing into the wind gets - 1 doubles it.
+    #[arg(long)]
+    rain_windward: Option<f32>,
+    /// The direction the wind blows towards, in degrees clockwise from the +x axis.
+    #[arg(long, allow_hyphen_values = true)]
+    rain_wind_direction: Option<f32>,
+    /// The fraction of the flow map kept from one iteration to the next.
+    #[arg(long)]
+    flow_memory: Option<f32>,
+    /// How many shallow-water time steps make up a single iteration.
+    #[arg(long)]
+    water_steps: Option<u32>,
+    /// The length of a single shallow-water time step.
+    #[arg(long)]
+    water_time_step: Option<f32>,
+    /// How much water rains onto every cell per unit of time.
+    #[arg(long)]
+    water_rain_rate: Option<f32>,
+    /// How strongly differences in water height push water between cells.
+    #[arg(long)]
+    water_gravity: Option<f32>,
+    /// How much sediment the water can carry for its speed and slope.
+    #[arg(long)]
+    water_sediment_capacity: Option<f32>,
+    /// How quickly the water dissolves terrain when it can carry more.
+    #[arg(long)]
+    water_dissolving: Option<f32>,
+    /// How quickly the water deposits sediment it can't carry.
+    #[arg(long)]
+    water_deposition: Option<f32>,
+    /// The fraction of the water that evaporates per unit of time.
+    #[arg(long)]
+    water_evaporation: Option<f32>,
+    /// The smallest tilt used for the carrying capacity, so flat ground still erodes.
+    #[arg(long)]
+    water_min_tilt: Option<f32>,
+    /// Water deeper than this carries no more sediment than water this deep.
+    #[arg(long)]
+    water_max_erosion_depth: Option<f32>,
+    /// Seed for the simulation - the same seed, input and parameters always give the same output.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Don't print progress.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    if let Some(chunk_size) = args.chunk_size {
+        return erode_chunked(&args, chunk_size);
+    }
+
+    let (heightmap, dims) = match read_heightmap(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hardness = match read_map(&args, &args.hardness, &args.hardness_channel, dims) {
+        Ok(hardness) => hardness,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let precipitation = match read_map(
+        &args,
+        &args.precipitation,
+        &args.precipitation_channel,
+        dims,
+    ) {
+        Ok(precipitation) => precipitation,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let params = match params(&args) {
+        Ok(params) => params,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = params.check_dims(dims) {
+        eprintln!("Can't erode {}: {e}", args.input.display());
+        return ExitCode::FAILURE;
+    }
+
+    if args.layers_output.is_some() && params.layers.is_empty() {
+        eprintln!("--layers-output needs layers to be set up in the --params file");
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(path) = &args.save_params {
+        if let Err(e) = params.save(path) {
+            eprintln!("Failed to save parameters to {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut simulator = ErosionSimulator::new(heightmap, dims);
+    simulator.params = params;
+    simulator.set_hardness(hardness);
+    simulator.set_precipitation(precipitation);
+    simulator.reseed(args.seed);
+
+    if !args.quiet {
+        eprintln!(
+            "Eroding {} ({}x{}) for {} iterations",
+            args.input.display(),
+            dims.0,
+            dims.1,
+            args.iterations
+        );
+    }
+
+    let start = Instant::now();
+    if simulator.params.pyramid_levels > 0 {
+        let pyramid_start = Instant::now();
+        let changes = simulator.run_pyramid();
+
+        if !args.quiet {
+            eprintln!(
+                "Pyramid levels took: {:?}, made {changes} changes",
+                pyramid_start.elapsed()
+            );
+        }
+    }
+
+    for _ in 0..args.iterations {
+        let iteration_start = Instant::now();
+        let changes = simulator.step();
+
+        if !args.quiet {
+            eprintln!(
+                "Iteration {} took: {:?}, made {changes} changes",
+                simulator.iteration(),
+                iteration_start.elapsed()
+            );
+        }
+    }
+
+    if let Err(e) = io::write_heightmap(&args.output, &simulator.heightmap, dims) {
+        eprintln!("Failed to write {}: {e}", args.output.display());
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(path) = &args.sediment_output {
+        let maps = [
+            ("erosion", simulator.erosion.as_slice()),
+            ("deposition", simulator.deposition.as_slice()),
+        ];
+        if let Err(e) = io::write_exr_channels(path, &maps, dims) {
+            eprintln!("Failed to write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(path) = &args.flow_output {
+        let maps = [("flow", simulator.flow.as_slice())];
+        if let Err(e) = io::write_exr_channels(path, &maps, dims) {
+            eprintln!("Failed to write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let (Some(path), Some(strata)) = (&args.layers_output, simulator.strata()) {
+        if let Err(e) = io::write_strata(path, strata, dims) {
+            eprintln!("Failed to write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if args.normal_output.is_some() || args.surface_output.is_some() {
+        let wrap = simulator.params.wraps();
+        let maps = SurfaceMaps::bake(&simulator.heightmap, dims, args.normal_strength, wrap);
+
+        if let Some(path) = &args.normal_output {
+            if let Err(e) = io::write_normal_map(path, &maps.normal_colours(), dims) {
+                eprintln!("Failed to write {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+
+        if let Some(path) = &args.surface_output {
+            let channels = [
+                ("slope", maps.slope.as_slice()),
+                ("curvature", maps.curvature.as_slice()),
+            ];
+            if let Err(e) = io::write_exr_channels(path, &channels, dims) {
+                eprintln!("Failed to write {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Some(path) = &args.mesh_output {
+        let options = MeshOptions {
+            spacing: args.mesh_spacing,
+            height_scale: args.mesh_height_scale,
+            step: args.mesh_step,
+        };
+        let mesh = TriangleMesh::from_heightmap(&simulator.heightmap, dims, &options);
+        if let Err(e) = mesh.and_then(|mesh| mesh.save(path)) {
+            eprintln!("Failed to write {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if !args.quiet {
+        eprintln!(
+            "Wrote {} after {:?}",
+            args.output.display(),
+            start.elapsed()
+        );
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Erodes a raw `.f32` heightmap a chunk at a time with a `ChunkedSimulator`, without ever
+/// loading the whole thing.
+fn erode_chunked(args: &Args, chunk_size: usize) -> ExitCode {
+    if !is_raw(&args.input) || !is_raw(&args.output) {
+        eprintln!("--chunk-size needs a raw .f32 input and output");
+        return ExitCode::FAILURE;
+    }
+    let Some(dims) = raw_dims(args) else {
+        eprintln!("--chunk-size needs the --width and --height of the input");
+        return ExitCode::FAILURE;
+    };
+
+    let params = match params(args) {
+        Ok(params) => params,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = params.check_dims(dims) {
+        eprintln!("Can't erode {}: {e}", args.input.display());
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(path) = &args.save_params {
+        if let Err(e) = params.save(path) {
+            eprintln!("Failed to save parameters to {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut input = match io::RawMap::open(&args.input, dims) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", args.input.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut output = match io::RawMap::create(&args.output, dims) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Failed to write {}: {e}", args.output.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut simulator = ChunkedSimulator::new(params, chunk_size);
+    simulator.reseed(args.seed);
+
+    if !args.quiet {
+        eprintln!(
+            "Eroding {} ({}x{}) for {} iterations, in chunks of {} with a halo of {}",
+            args.input.display(),
+            dims.0,
+            dims.1,
+            args.iterations,
+            simulator.chunk_size(),
+            simulator.halo()
+        );
+    }
+
+    let start = Instant::now();
+    let mut chunk_start = Instant::now();
+    let result = simulator.run(&mut input, &mut output, args.iterations, |done, total| {
+        if !args.quiet {
+            eprintln!("Pass {done}/{total} took: {:?}", chunk_start.elapsed());
+        }
+        chunk_start = Instant::now();
+    });
+
+    match result {
+        Ok(changes) => {
+            if !args.quiet {
+                eprintln!(
+                    "Wrote {} after {:?}, made {changes} changes",
+                    args.output.display(),
+                    start.elapsed()
+                );
+            }
+
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to erode {}: {e}", args.input.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Reads the input heightmap in the format its extension says, then rescales it as asked.
+fn read_heightmap(args: &Args) -> Result<(Vec<f32>, (usize, usize)), String> {
+    if is_raw(&args.input) && raw_dims(args).is_none() {
+        return Err("A raw .f32 input needs --width and --height".to_string());
+    }
+
+    let (mut heightmap, dims) = io::read_heightmap(&args.input, raw_dims(args))
+        .map_err(|e| format!("Failed to read {}: {e}", args.input.display()))?;
+
+    height_scale(args).apply(&mut heightmap);
+
+    Ok((heightmap, dims))
+}
+
+/// How to rescale the input's heights, from `--height-scale` or `--normalize`.
+fn height_scale(args: &Args) -> HeightScale {
+    match (args.height_scale, args.normalize.as_deref()) {
+        (Some(scale), _) => HeightScale::Scale(scale),
+        (None, Some(&[min, max])) => HeightScale::Normalize { min, max },
+        _ => HeightScale::default(),
+    }
+}
+
+/// Whether a heightmap is stored as raw little-endian floats, from its `.f32` extension.
+fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("f32"))
+}
+
+/// The dimensions of a raw input, from `--width` and `--height`.
+fn raw_dims(args: &Args) -> Option<(usize, usize)> {
+    Some((args.width?, args.height?))
+}
+
+/// Builds the simulation parameters from the preset or parameter file, then the individual flags.
+fn params(args: &Args) -> Result<ErosionParams, String> {
+    let mut params = if let Some(name) = &args.preset {
+        ErosionParams::preset(name).ok_or_else(|| {
+            format!(
+                "Unknown preset {name:?}, expected one of: {}",
+                PRESETS.join(", ")
+            )
+        })?
+    } else if let Some(path) = &args.params {
+        ErosionParams::load(path)
+            .map_err(|e| format!("Failed to load parameters from {}: {e}", path.display()))?
+    } else {
+        ErosionParams::default()
+    };
+
+    if let Some(model) = args.model {
+        params.model = model;
+    }
+    if let Some(gravity) = args.gravity {
+        params.gravity = gravity;
+    }
+    if let Some(capacity) = args.capacity {
+        params.capacity = capacity;
+    }
+    if let Some(inertia) = args.inertia {
+        params.inertia = inertia;
+    }
+    if let Some(erosion_factor) = args.erosion_factor {
+        params.erosion_factor = erosion_factor;
+    }
+    if let Some(deposition_factor) = args.deposition_factor {
+        params.deposition_factor = deposition_factor;
+    }
+    if let Some(diameter) = args.diameter {
+        params.diameter = diameter;
+    }
+    if let Some(lifetime) = args.lifetime {
+        params.lifetime = lifetime;
+    }
+    if let Some(starting_mass) = args.starting_mass {
+        params.starting_mass = starting_mass;
+    }
+    if let Some(evaporation) = args.evaporation {
+        params.evaporation = evaporation;
+    }
+    if let Some(min_velocity) = args.min_velocity {
+        params.min_velocity = min_velocity;
+    }
+    if let Some(boundary) = args.boundary {
+        params.boundary = boundary;
+    }
+    if let Some(drops) = args.drops {
+        params.drop_count = drops;
+    }
+    if let Some(tiled) = args.tiled {
+        params.tiled = tiled;
+    }
+    if let Some(pyramid_levels) = args.pyramid_levels {
+        params.pyramid_levels = pyramid_levels;
+    }
+    if let Some(pyramid_iterations) = args.pyramid_iterations {
+        params.pyramid_iterations = pyramid_iterations;
+    }
+    if let Some(thermal_talus) = args.thermal_talus {
+        params.thermal_talus = thermal_talus;
+    }
+    if let Some(thermal_rate) = args.thermal_rate {
+        params.thermal_rate = thermal_rate;
+    }
+    if let Some(thermal_interval) = args.thermal_interval {
+        params.thermal_interval = thermal_interval;
+    }
+    if let Some(hardening) = args.hardening {
+        params.hardening = hardening;
+    }
+    if let Some(rain_elevation) = args.rain_elevation {
+        params.rain_elevation = rain_elevation;
+    }
+    if let Some(rain_windward) = args.rain_windward {
+        params.rain_windward = rain_windward;
+    }
+    if let Some(rain_wind_direction) = args.rain_wind_direction {
+        params.rain_wind_direction = rain_wind_direction;
+    }
+    if let Some(flow_memory) = args.flow_memory {
+        params.flow_memory = flow_memory;
+    }
+
+    let water = &mut params.shallow_water;
+    if let Some(steps) = args.water_steps {
+        water.steps = steps;
+    }
+    if let Some(time_step) = args.water_time_step {
+        water.time_step = time_step;
+    }
+    if let Some(rain_rate) = args.water_rain_rate {
+        water.rain_rate = rain_rate;
+    }
+    if let Some(gravity) = args.water_gravity {
+        water.gravity = gravity;
+    }
+    if let Some(sediment_capacity) = args.water_sediment_capacity {
+        water.sediment_capacity = sediment_capacity;
+    }
+    if let Some(dissolving) = args.water_dissolving {
+        water.dissolving = dissolving;
+    }
+    if let Some(deposition) = args.water_deposition {
+        water.deposition = deposition;
+    }
+    if let Some(evaporation) = args.water_evaporation {
+        water.evaporation = evaporation;
+    }
+    if let Some(min_tilt) = args.water_min_tilt {
+        water.min_tilt = min_tilt;
+    }
+    if let Some(max_erosion_depth) = args.water_max_erosion_depth {
+        water.max_erosion_depth = max_erosion_depth;
+    }
+
+    Ok(params)
+}
+
+/// Reads a map like the rock hardness from its own file or a channel of the input, if either
+/// was given. Its own file can be in any format the input can, and raw files are read at
+/// the heightmap's dimensions `dims`.
+fn read_map(
+    args: &Args,
+    file: &Option<PathBuf>,
+    channel: &Option<String>,
+    dims: (usize, usize),
+) -> Result<Option<Vec<f32>>, String> {
+    let (path, loaded) = if let Some(path) = file {
+        (path, io::read_heightmap(path, Some(dims)))
+    } else if let Some(channel) = channel {
+        (&args.input, io::read_exr_channel(&args.input, channel))
+    } else {
+        return Ok(None);
+    };
+
+    let (map, map_dims) =
+        loaded.map_err(|e| format!("Failed to read a map from {}: {e}", path.display()))?;
+
+    if map_dims != dims {
+        return Err(format!(
+            "The map in {} is {}x{}, but the heightmap is {}x{}",
+            path.display(),
+            map_dims.0,
+            map_dims.1,
+            dims.0,
+            dims.1
+        ));
+    }
+
+    Ok(Some(map))
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use clap::Parser;
+
+use erosion_simulation_lib::generate::{generate, NoiseKind, TerrainParams};
+use erosion_simulation_lib::io;
+
+/// Generate a base terrain to erode, from fractal noise.
+///
+/// Writes an EXR heightmap from `0.0` to `1.0` (or raw floats for `.f32` files)
+/// that `erode` can take straight away, so a landscape can be made from nothing.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Where to write the heightmap.
+    output: PathBuf,
+    /// The width of the heightmap.
+    #[arg(long, default_value_t = 1024)]
+    width: usize,
+    /// The height of the heightmap.
+    #[arg(long, default_value_t = 1024)]
+    height: usize,
+    /// The kind of noise ("fbm" for rolling hills or "ridged" for mountain ranges).
+    #[arg(long, default_value_t = NoiseKind::Fbm)]
+    noise: NoiseKind,
+    /// The size of the biggest features, in cells.
+    #[arg(long, default_value_t = TerrainParams::default().scale)]
+    scale: f32,
+    /// How many layers of ever finer noise are added together.
+    #[arg(long, default_value_t = TerrainParams::default().octaves)]
+    octaves: u32,
+    /// How much finer each octave is than the one before.
+    #[arg(long, default_value_t = TerrainParams::default().lacunarity)]
+    lacunarity: f32,
+    /// How much weaker each octave is than the one before.
+    #[arg(long, default_value_t = TerrainParams::default().persistence)]
+    persistence: f32,
+    /// How far, in cells, to push the terrain around with domain warping - 0 turns it off.
+    #[arg(long, default_value_t = TerrainParams::default().warp)]
+    warp: f32,
+    /// Seed for the noise - the same seed and parameters always give the same terrain.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Don't print progress.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let params = TerrainParams {
+        noise: args.noise,
+        seed: args.seed,
+        scale: args.scale,
+        octaves: args.octaves,
+        lacunarity: args.lacunarity,
+        persistence: args.persistence,
+        warp: args.warp,
+    };
+    let dims = (args.width, args.height);
+
+    let start = Instant::now();
+    let heightmap = generate(dims, &params);
+
+    if let Err(e) = io::write_heightmap(&args.output, &heightmap, dims) {
+        eprintln!("Failed to write {}: {e}", args.output.display());
+        return ExitCode::FAILURE;
+    }
+
+    if !args.quiet {
+        eprintln!(
+            "Generated {} ({}x{}) in {:?}",
+            args.output.display(),
+            dims.0,
+            dims.1,
+            start.elapsed()
+        );
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,178 @@
+use nalgebra::Vector2;
+
+/// How many positions within a cell, along each axis, the brush is built for.
+const PHASES: usize = 4;
+
+/// A precomputed erosion brush - the cells a `Raindrop` erodes from and deposits
+/// onto, with how much of the material each one gets.
+///
+/// Built once per diameter and map, then shared by every `Raindrop`, so a step
+/// only has to look up weights rather than work out a distance for every cell
+/// under the brush.
+///
+/// # Explanation
+///
+/// A `Raindrop` erodes every cell whose corner is within `diameter / 2` of it,
+/// each weighted by `(1 - distance / radius)^2`, with the weights normalised to add
+/// up to `1.0`. Which cells those are depends on where in its cell the drop is, so
+/// the brush is built for a grid of `PHASES` by `PHASES` positions within a cell and
+/// each drop uses the nearest one. That's an approximation - the weights are slightly
+/// different from working them out at the drop's exact position - but it covers about
+/// `pi * radius^2` cells on average, like the exact brush would. The offsets are also stored as index offsets
+/// for a map of this width, so away from the edges the brush is just a list of
+/// additions. Near the edges, cells off the map are skipped and the rest share out
+/// their weight - unless the map wraps around, in which case they land on the
+/// opposite side instead.
+#[derive(Debug, Clone)]
+pub struct Brush {
+    // The diameter the brush was built with
+    diameter: f32,
+    // The dimensions of the map the brush was built for
+    dims: (usize, usize),
+    // Whether cells off one side of the map wrap around to the other
+    wrap: bool,
+    // How many cells the brush reaches out from the cell a drop is in, at any position
+    reach: usize,
+    // Every cell under the brush as `(x offset, y offset, weight)`, for every position
+    // within a cell, row by row
+    cells: Vec<Vec<(isize, isize, f32)>>,
+    // The index offset of every cell under the brush, for every position within a cell
+    offsets: Vec<Vec<isize>>,
+}
+
+impl Brush {
+    /// Build a brush with the given diameter for a map with dimensions `dims`, which
+    /// repeats past its edges if `wrap` is set.
+    pub fn new(diameter: f32, dims: (usize, usize), wrap: bool) -> Self {
+        let radius = diameter / 2.0;
+        let search = radius.max(0.0).ceil() as isize + 1;
+
+        let cells: Vec<Vec<(isize, isize, f32)>> = (0..PHASES * PHASES)
+            .map(|phase| {
+                // The middle of this phase's part of the cell
+                let centre_x = ((phase % PHASES) as f32 + 0.5) / PHASES as f32;
+                let centre_y = ((phase / PHASES) as f32 + 0.5) / PHASES as f32;
+
+                let mut cells = Vec::new();
+                for y in -search..=search {
+                    for x in -search..=search {
+                        let distance =
+                            ((x as f32 - centre_x).powi(2) + (y as f32 - centre_y).powi(2)).sqrt();
+                        if distance <= radius {
+                            cells.push((x, y, (1.0 - distance / radius).powi(2)));
+                        }
+                    }
+                }
+
+                // A brush too small to reach any corner just uses the drop's own cell
+                let weight_sum: f32 = cells.iter().map(|(_, _, weight)| weight).sum();
+                if weight_sum <= 0.0 {
+                    return vec![(0, 0, 1.0)];
+                }
+                cells
+                    .iter_mut()
+                    .for_each(|(_, _, weight)| *weight /= weight_sum);
+
+                cells
+            })
+            .collect();
+
+        let reach = cells
+            .iter()
+            .flatten()
+            .map(|&(x, y, _)| x.unsigned_abs().max(y.unsigned_abs()))
+            .max()
+            .unwrap_or(0);
+
+        let offsets = cells
+            .iter()
+            .map(|cells| {
+                cells
+                    .iter()
+                    .map(|&(x, y, _)| y * dims.0 as isize + x)
+                    .collect()
+            })
+            .collect();
+
+        Brush {
+            diameter,
+            dims,
+            wrap,
+            reach,
+            cells,
+            offsets,
+        }
+    }
+
+    /// The diameter the brush was built with.
+    pub fn diameter(&self) -> f32 {
+        self.diameter
+    }
+
+    /// The dimensions of the map the brush was built for.
+    pub fn dims(&self) -> (usize, usize) {
+        self.dims
+    }
+
+    /// Whether the brush wraps around the edges of the map.
+    pub fn wrap(&self) -> bool {
+        self.wrap
+    }
+
+    /// Call `f` with the index and weight of every cell under the brush when it's
+    /// centred on the cell containing `position`. The weights add up to `1.0`,
+    /// unless the brush is entirely off the map.
+    pub fn for_each(&self, position: Vector2<f32>, mut f: impl FnMut(usize, f32)) {
+        let (width, height) = self.dims;
+        let x = position.x.floor() as isize;
+        let y = position.y.floor() as isize;
+        let reach = self.reach as isize;
+
+        // Pick the brush built for the nearest position within the cell
+        let phase_x = (((position.x - x as f32) * PHASES as f32) as usize).min(PHASES - 1);
+        let phase_y = (((position.y - y as f32) * PHASES as f32) as usize).min(PHASES - 1);
+        let phase = phase_y * PHASES + phase_x;
+        let cells = &self.cells[phase];
+
+        // The whole brush fits on the map, so every offset can be used as it is
+        if x >= reach && y >= reach && x + reach < width as isize && y + reach < height as isize {
+            let centre = y * width as isize + x;
+            for (offset, (_, _, weight)) in self.offsets[phase].iter().zip(cells) {
+                f((centre + offset) as usize, *weight);
+            }
+
+            return;
+        }
+
+        // Wrap the cells off the map around to the other side
+        if self.wrap {
+            for (dx, dy, weight) in cells {
+                let cell_x = (x + dx).rem_euclid(width as isize) as usize;
+                let cell_y = (y + dy).rem_euclid(height as isize) as usize;
+                f(cell_y * width + cell_x, *weight);
+            }
+
+            return;
+        }
+
+        // Otherwise share the weight of the cells off the map between the rest
+        let on_map = |&&(dx, dy, _): &&(isize, isize, f32)| {
+            let (cell_x, cell_y) = (x + dx, y + dy);
+            cell_x >= 0 && cell_y >= 0 && cell_x < width as isize && cell_y < height as isize
+        };
+
+        let weight_sum: f32 = cells
+            .iter()
+            .filter(on_map)
+            .map(|(_, _, weight)| weight)
+            .sum();
+        if weight_sum <= 0.0 {
+            return;
+        }
+
+        for (dx, dy, weight) in cells.iter().filter(on_map) {
+            let index = (y + dy) as usize * width + (x + dx) as usize;
+            f(index, weight / weight_sum);
+        }
+    }
+}
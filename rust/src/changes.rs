@@ -0,0 +1,121 @@
+/// How many cells in a row make up one page of a `ChangeBuffer`.
+const PAGE: usize = 256;
+
+/// Marks a page of a `ChangeBuffer` that hasn't been touched.
+const UNTOUCHED: u32 = u32::MAX;
+
+/// Adds up changes to a map cell by cell, merging changes to the same cell in
+/// constant time.
+///
+/// # Explanation
+///
+/// The map is split into pages of `PAGE` cells, and storage for a page is only
+/// handed out the first time one of its cells is changed - so a buffer takes up
+/// 5 bytes for every cell of every page touched, plus 4 bytes for every page of
+/// the map, rather than holding a value for every cell. The cells that have been
+/// touched are also listed, so only those need visiting to read the changes back
+/// out.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeBuffer {
+    // How many cells the buffer covers
+    cells: usize,
+    // Where every page's storage starts, in pages, or `UNTOUCHED`
+    pages: Vec<u32>,
+    // The pages that have storage, so it can be handed back
+    touched_pages: Vec<usize>,
+    // The change to every cell of every page with storage
+    values: Vec<f32>,
+    // Whether each cell of every page with storage has been touched
+    touched: Vec<bool>,
+    // The cells that have been touched, in the order they were first touched
+    order: Vec<usize>,
+}
+
+impl ChangeBuffer {
+    /// Create an empty buffer covering `cells` cells.
+    pub fn new(cells: usize) -> Self {
+        ChangeBuffer {
+            cells,
+            pages: vec![UNTOUCHED; cells.div_ceil(PAGE)],
+            ..Default::default()
+        }
+    }
+
+    /// The number of cells the buffer covers.
+    pub fn cells(&self) -> usize {
+        self.cells
+    }
+
+    /// The number of distinct cells changed since the buffer was last drained.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Whether no cells have been changed since the buffer was last drained.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Add `amount` to the change to cell `index`.
+    pub fn add(&mut self, index: usize, amount: f32) {
+        let page = index / PAGE;
+        if self.pages[page] == UNTOUCHED {
+            self.pages[page] = self.touched_pages.len() as u32;
+            self.touched_pages.push(page);
+            self.values.resize(self.values.len() + PAGE, 0.0);
+            self.touched.resize(self.touched.len() + PAGE, false);
+        }
+
+        let slot = self.slot(index);
+        if !self.touched[slot] {
+            self.touched[slot] = true;
+            self.order.push(index);
+        }
+
+        self.values[slot] += amount;
+    }
+
+    /// Take every change out of the buffer as a compact list of `(amount, index)`, in the
+    /// order the cells were first touched, leaving the buffer empty.
+    pub fn drain(&mut self) -> Vec<(f32, usize)> {
+        let changes = self
+            .order
+            .iter()
+            .map(|&index| (self.values[self.slot(index)], index))
+            .collect();
+        self.clear();
+
+        changes
+    }
+
+    /// Add every change onto `map`, leaving the buffer empty.
+    ///
+    /// # Returns
+    ///
+    /// The number of cells that were changed.
+    pub fn drain_into(&mut self, map: &mut [f32]) -> usize {
+        let count = self.order.len();
+
+        for &index in &self.order {
+            map[index] += self.values[self.slot(index)];
+        }
+        self.clear();
+
+        count
+    }
+
+    /// Where the change to cell `index` is kept, once its page has storage.
+    fn slot(&self, index: usize) -> usize {
+        self.pages[index / PAGE] as usize * PAGE + index % PAGE
+    }
+
+    /// Hand back the storage of every page, leaving the buffer empty.
+    fn clear(&mut self) {
+        for page in self.touched_pages.drain(..) {
+            self.pages[page] = UNTOUCHED;
+        }
+        self.values.clear();
+        self.touched.clear();
+        self.order.clear();
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use nalgebra::Vector2;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::error::{Error, Result};
+use crate::layers::Strata;
+use crate::params::{ErosionParams, LayerParams};
+use crate::shallow_water::ShallowWater;
+use crate::simulator::Progress;
+
+/// The bytes every checkpoint file starts with.
+const MAGIC: &[u8; 8] = b"ERODCKPT";
+/// The version of the checkpoint format, bumped whenever it changes.
+const VERSION: u32 = 1;
+
+/// Everything an `ErosionSimulator` needs to carry on exactly where it stopped, from
+/// `ErosionSimulator::checkpoint`.
+///
+/// Anything the simulator can work out again, like where the rain falls, is left out.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// The parameters the simulation was running with.
+    pub params: ErosionParams,
+    /// The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`.
+    pub dims: (usize, usize),
+    /// The heightmap, stored row by row.
+    pub heightmap: Vec<f32>,
+    /// How much material has been removed from every cell in total.
+    pub erosion: Vec<f32>,
+    /// How much material has been laid down on every cell in total.
+    pub deposition: Vec<f32>,
+    /// How much water flows through every cell.
+    pub flow: Vec<f32>,
+    /// The rock hardness of every cell, if there is a hardness map.
+    pub hardness: Option<Vec<f32>>,
+    /// How much rain falls on every cell relative to the others, if it isn't even.
+    pub precipitation: Option<Vec<f32>>,
+    /// The material layers under every cell, if there are any.
+    pub strata: Option<Strata>,
+    /// The iteration count, RNG and shallow-water state.
+    pub progress: Progress,
+}
+
+impl Checkpoint {
+    /// Write the checkpoint to a file.
+    ///
+    /// # Explanation
+    ///
+    /// The file is little-endian binary: a magic number and version, the parameters as
+    /// JSON, the dimensions, iteration count and RNG state, then every map as raw
+    /// `f32`s. Maps that might not exist are preceded by a byte saying whether they do.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(MAGIC)?;
+        write_u32(&mut file, VERSION)?;
+        write_json(&mut file, &self.params)?;
+
+        write_u64(&mut file, self.dims.0 as u64)?;
+        write_u64(&mut file, self.dims.1 as u64)?;
+        write_u64(&mut file, self.progress.iteration as u64)?;
+        write_u64(&mut file, self.progress.seed)?;
+        file.write_all(&self.progress.rng.get_seed())?;
+        write_u64(&mut file, self.progress.rng.get_stream())?;
+        file.write_all(&self.progress.rng.get_word_pos().to_le_bytes())?;
+
+        for map in [&self.heightmap, &self.erosion, &self.deposition, &self.flow] {
+            write_floats(&mut file, map)?;
+        }
+
+        for map in [&self.hardness, &self.precipitation] {
+            write_bool(&mut file, map.is_some())?;
+            if let Some(map) = map {
+                write_floats(&mut file, map)?;
+            }
+        }
+
+        write_bool(&mut file, self.strata.is_some())?;
+        if let Some(strata) = &self.strata {
+            write_json(&mut file, &strata.layers())?;
+            write_floats(&mut file, strata.packed_thickness())?;
+        }
+
+        write_bool(&mut file, self.progress.shallow_water.is_some())?;
+        if let Some(water) = &self.progress.shallow_water {
+            write_floats(&mut file, &water.water)?;
+            write_floats(&mut file, &water.sediment)?;
+            let flux: Vec<f32> = water.flux().iter().flatten().copied().collect();
+            write_floats(&mut file, &flux)?;
+            let velocity: Vec<f32> = water.velocity().iter().flat_map(|v| [v.x, v.y]).collect();
+            write_floats(&mut file, &velocity)?;
+            write_bool(&mut file, water.wraps())?;
+        }
+
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Read a checkpoint written by `save`.
+    ///
+    /// Fails if the file isn't a checkpoint, was written by a different version of
+    /// the format, or any of its maps don't match its dimensions.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Format("the file isn't a checkpoint".to_string()));
+        }
+        let version = read_u32(&mut file)?;
+        if version != VERSION {
+            return Err(Error::Format(format!(
+                "the checkpoint is version {version}, but only version {VERSION} can be read"
+            )));
+        }
+
+        let params: ErosionParams = read_json(&mut file)?;
+        params.validate()?;
+
+        let dims = (read_u64(&mut file)? as usize, read_u64(&mut file)? as usize);
+        let cells = dims
+            .0
+            .checked_mul(dims.1)
+            .ok_or_else(|| Error::Format("the checkpoint's dimensions are too big".to_string()))?;
+        let iteration = read_u64(&mut file)? as usize;
+        let seed = read_u64(&mut file)?;
+
+        let mut rng_seed = [0; 32];
+        file.read_exact(&mut rng_seed)?;
+        let mut rng = ChaCha8Rng::from_seed(rng_seed);
+        rng.set_stream(read_u64(&mut file)?);
+        let mut word_pos = [0; 16];
+        file.read_exact(&mut word_pos)?;
+        rng.set_word_pos(u128::from_le_bytes(word_pos));
+
+        let heightmap = read_map(&mut file, cells, "heightmap")?;
+        let erosion = read_map(&mut file, cells, "erosion map")?;
+        let deposition = read_map(&mut file, cells, "deposition map")?;
+        let flow = read_map(&mut file, cells, "flow map")?;
+
+        let hardness = match read_bool(&mut file)? {
+            true => Some(read_map(&mut file, cells, "hardness map")?),
+            false => None,
+        };
+        let precipitation = match read_bool(&mut file)? {
+            true => Some(read_map(&mut file, cells, "precipitation map")?),
+            false => None,
+        };
+
+        let strata = match read_bool(&mut file)? {
+            true => {
+                let layers: Vec<LayerParams> = read_json(&mut file)?;
+                let thickness = read_floats(&mut file)?;
+                let strata = Strata::from_packed(&layers, thickness, cells)
+                    .ok_or_else(|| mismatch("material layers"))?;
+                Some(strata)
+            }
+            false => None,
+        };
+
+        let shallow_water = match read_bool(&mut file)? {
+            true => {
+                let water = read_floats(&mut file)?;
+                let sediment = read_floats(&mut file)?;
+                let flux = read_floats(&mut file)?
+                    .chunks_exact(4)
+                    .map(|flux| [flux[0], flux[1], flux[2], flux[3]])
+                    .collect();
+                let velocity = read_floats(&mut file)?
+                    .chunks_exact(2)
+                    .map(|velocity| Vector2::new(velocity[0], velocity[1]))
+                    .collect();
+                let wrap = read_bool(&mut file)?;
+
+                let mut grid = ShallowWater::from_state(dims, water, sediment, flux, velocity)
+                    .ok_or_else(|| mismatch("shallow water"))?;
+                grid.set_wrap(wrap);
+                Some(grid)
+            }
+            false => None,
+        };
+
+        Ok(Checkpoint {
+            params,
+            dims,
+            heightmap,
+            erosion,
+            deposition,
+            flow,
+            hardness,
+            precipitation,
+            strata,
+            progress: Progress {
+                iteration,
+                seed,
+                rng,
+                shallow_water,
+            },
+        })
+    }
+}
+
+/// The error for part of a checkpoint that doesn't match its dimensions.
+fn mismatch(what: &str) -> Error {
+    Error::Format(format!(
+        "the checkpoint's {what} doesn't match its dimensions"
+    ))
+}
+
+fn write_u32(file: &mut impl Write, value: u32) -> Result<()> {
+    file.write_all(&value.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_u64(file: &mut impl Write, value: u64) -> Result<()> {
+    file.write_all(&value.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_bool(file: &mut impl Write, value: bool) -> Result<()> {
+    file.write_all(&[value as u8])?;
+
+    Ok(())
+}
+
+/// Writes how many values there are, then the values themselves.
+fn write_floats(file: &mut impl Write, values: &[f32]) -> Result<()> {
+    write_u64(file, values.len() as u64)?;
+    let bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    file.write_all(&bytes)?;
+
+    Ok(())
+}
+
+/// Writes `value` as JSON, preceded by its length in bytes.
+fn write_json(file: &mut impl Write, value: &impl serde::Serialize) -> Result<()> {
+    let json = serde_json::to_vec(value).map_err(|e| Error::Format(e.to_string()))?;
+    write_u64(file, json.len() as u64)?;
+    file.write_all(&json)?;
+
+    Ok(())
+}
+
+fn read_u32(file: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(file: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bool(file: &mut impl Read) -> Result<bool> {
+    let mut byte = [0];
+    file.read_exact(&mut byte)?;
+
+    Ok(byte[0] != 0)
+}
+
+/// Reads values written by `write_floats`.
+fn read_floats(file: &mut impl Read) -> Result<Vec<f32>> {
+    let size = read_u64(file)?
+        .checked_mul(4)
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or_else(|| Error::Format("the checkpoint has too many values".to_string()))?;
+    let mut bytes = Vec::new();
+    file.by_ref().take(size as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != size {
+        return Err(Error::Format("the checkpoint ends too soon".to_string()));
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect())
+}
+
+/// Reads values written by `write_floats`, checking there's one for every cell.
+fn read_map(file: &mut impl Read, cells: usize, what: &str) -> Result<Vec<f32>> {
+    let map = read_floats(file)?;
+    if map.len() != cells {
+        return Err(mismatch(what));
+    }
+
+    Ok(map)
+}
+
+/// Reads a value written by `write_json`.
+fn read_json<T: serde::de::DeserializeOwned>(file: &mut impl Read) -> Result<T> {
+    let len = read_u64(file)?;
+    let mut json = Vec::new();
+    file.by_ref().take(len).read_to_end(&mut json)?;
+
+    serde_json::from_slice(&json).map_err(|e| Error::Format(e.to_string()))
+}
@@ -0,0 +1,322 @@
+use crate::error::{Error, Result};
+use crate::io::RawMap;
+use crate::params::{Boundary, ErosionModel, ErosionParams};
+use crate::simulator::ErosionSimulator;
+use crate::tiles::Window;
+
+/// Erodes heightmaps too big to fit in memory, streaming them from and to disk a
+/// chunk at a time.
+///
+/// # Explanation
+///
+/// The map is cut into square chunks, and each one is eroded on its own with an
+/// `ErosionSimulator` over a window covering the chunk plus a halo around it.
+/// Drops only start on the chunk, and the halo is as wide as a `Raindrop` can
+/// travel - so every change a chunk's drops make lands inside its window, and the
+/// whole window is written back to the output.
+///
+/// The input is copied to the output first, and the map is then eroded one
+/// iteration at a time: every chunk runs a single iteration on a window read from
+/// the output and writes it back, before any chunk starts the next. So where a
+/// chunk's halo overlaps its neighbours, it sees them as they are after the same
+/// number of iterations, and its drops carry on carving the valleys they left
+/// rather than eroding a stale copy of them. Chunks run in the same four-colour
+/// order as `Tiling`, so chunks of the same colour never share any cells.
+///
+/// Only one window is in memory at a time, at the cost of reading and writing every
+/// window once per iteration.
+#[derive(Debug, Clone)]
+pub struct ChunkedSimulator {
+    /// The parameters every chunk is eroded with. `drop_count` is for the whole map,
+    /// and is shared out between the windows by their size.
+    pub params: ErosionParams,
+    // The width and height of a chunk, not counting its halo
+    chunk_size: usize,
+    // The seed every chunk's own seed is made from
+    seed: u64,
+}
+
+impl ChunkedSimulator {
+    /// Create a `ChunkedSimulator` with the given parameters, cutting maps into chunks
+    /// `chunk_size` cells across, and a seed of `0`.
+    pub fn new(params: ErosionParams, chunk_size: usize) -> Self {
+        ChunkedSimulator {
+            params,
+            chunk_size,
+            seed: 0,
+        }
+    }
+
+    /// Set the seed every chunk's own seed is made from.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// The seed every chunk's own seed is made from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// How far a chunk's window reaches past each side of it - as far as a `Raindrop`
+    /// can travel in its lifetime, plus its brush.
+    pub fn halo(&self) -> usize {
+        self.params.lifetime as usize + (self.params.diameter / 2.0).ceil() as usize + 1
+    }
+
+    /// The width and height of a chunk, not counting its halo. This is at least twice
+    /// the halo, so a chunk's window only ever overlaps those of its neighbours.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.max(self.halo() * 2)
+    }
+
+    /// Erode the heightmap in `input` for `iterations` iterations, writing the result
+    /// to `output`.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The heightmap to erode.
+    /// * `output` - Where the eroded heightmap goes. It must be the same size as `input`,
+    ///   and anything already in it is overwritten.
+    /// * `iterations` - How many full-resolution iterations to run on every chunk, after
+    ///   its pyramid levels if it has any.
+    /// * `progress` - Called every time a chunk has been through a pass - its pyramid
+    ///   levels or an iteration - with the number of passes done so far over every
+    ///   chunk and the total.
+    ///
+    /// # Returns
+    ///
+    /// The number of changes made over every chunk.
+    pub fn run(
+        &self,
+        input: &mut RawMap,
+        output: &mut RawMap,
+        iterations: usize,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize> {
+        let dims = input.dims();
+        if output.dims() != dims {
+            return Err(Error::Format(format!(
+                "the output is {}x{}, but the input is {}x{}",
+                output.dims().0,
+                output.dims().1,
+                dims.0,
+                dims.1
+            )));
+        }
+        if self.params.model != ErosionModel::Droplet {
+            return Err(Error::Format(
+                "chunked erosion only runs the droplet model".to_string(),
+            ));
+        }
+
+        self.params.check_dims(dims)?;
+
+        let (column_spans, row_spans) = self.chunks(dims)?;
+        let pyramid = self.params.pyramid_levels > 0;
+        let total = column_spans.len() * row_spans.len() * (iterations + pyramid as usize);
+
+        // Start the output off as the input, so every chunk can read what its
+        // neighbours have done from it
+        for rows in &row_spans {
+            for columns in &column_spans {
+                let chunk = Window {
+                    origin: (columns.start, rows.start),
+                    dims: (columns.end - columns.start, rows.end - rows.start),
+                };
+                output.write_window(&chunk, &input.read_window(&chunk)?)?;
+            }
+        }
+
+        // Carve the big valleys first, then run the iterations one at a time over
+        // every chunk
+        let passes = pyramid
+            .then_some(Pass::Pyramid)
+            .into_iter()
+            .chain((0..iterations).map(Pass::Iteration));
+
+        let mut changes = 0;
+        let mut done = 0;
+        for pass in passes {
+            for colour in 0..4 {
+                for (row, rows) in row_spans.iter().enumerate() {
+                    for (column, columns) in column_spans.iter().enumerate() {
+                        if (row % 2) * 2 + column % 2 != colour {
+                            continue;
+                        }
+
+                        let chunk = row * column_spans.len() + column;
+                        changes += self.erode_chunk(output, dims, columns, rows, chunk, pass)?;
+
+                        done += 1;
+                        progress(done, total);
+                    }
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Run one pass over the window around a chunk, reading it from `output` and
+    /// writing it back.
+    ///
+    /// # Returns
+    ///
+    /// The number of changes made.
+    fn erode_chunk(
+        &self,
+        output: &mut RawMap,
+        dims: (usize, usize),
+        columns: &Axis,
+        rows: &Axis,
+        chunk: usize,
+        pass: Pass,
+    ) -> Result<usize> {
+        let window = Window {
+            origin: (columns.origin, rows.origin),
+            dims: (columns.len, rows.len),
+        };
+
+        // Only rain on the chunk itself, so no drop can leave the window
+        let rain = (0..window.cells())
+            .map(|index| {
+                let (x, y) = (index % window.dims.0, index / window.dims.0);
+                (columns.contains(x) && rows.contains(y)) as u8 as f32
+            })
+            .collect();
+
+        let chunk_cells = (columns.end - columns.start) * (rows.end - rows.start);
+        let mut simulator = ErosionSimulator::new(output.read_window(&window)?, window.dims);
+        simulator.params = self.chunk_params(chunk_cells, dims);
+        simulator.set_precipitation(Some(rain));
+        simulator.reseed(chunk_seed(self.seed, chunk));
+
+        let changes = match pass {
+            Pass::Pyramid => simulator.run_pyramid(),
+            Pass::Iteration(iteration) => {
+                // Every iteration draws its drops from a stream of its own, and thermal
+                // passes land on the same iterations as they would on the whole map
+                let mut progress = simulator.progress();
+                progress.iteration = iteration;
+                progress.rng.set_stream(iteration as u64);
+                simulator.resume(progress);
+
+                simulator.step()
+            }
+        };
+
+        output.write_window(&window, &simulator.heightmap)?;
+
+        Ok(changes)
+    }
+
+    /// Cut each side of a map with dimensions `dims` into chunks, giving the span of
+    /// every chunk's window across and down.
+    ///
+    /// There's a whole number of chunks each way, with the last one stretching to
+    /// cover what's left over - so none is smaller than `chunk_size`, unless the
+    /// map itself is.
+    fn chunks(&self, dims: (usize, usize)) -> Result<(Vec<Axis>, Vec<Axis>)> {
+        let chunk_size = self.chunk_size();
+        let count = |size: usize| (size / chunk_size).max(1);
+
+        // A window has to have a different chunk on each side of it to wrap onto
+        if self.params.wraps() && (count(dims.0) < 2 || count(dims.1) < 2) {
+            return Err(Error::Format(format!(
+                "a wrapping map has to be at least two chunks ({chunk_size} cells) across and down"
+            )));
+        }
+
+        let split = |size: usize| {
+            let count = count(size);
+            (0..count)
+                .map(|chunk| {
+                    let end = if chunk + 1 == count {
+                        size
+                    } else {
+                        (chunk + 1) * chunk_size
+                    };
+                    self.axis(chunk * chunk_size, end, size)
+                })
+                .collect::<Vec<_>>()
+        };
+        let (columns, rows) = (split(dims.0), split(dims.1));
+
+        Ok((columns, rows))
+    }
+
+    /// Work out the span of a chunk's window along one side of the map, which is
+    /// `size` cells long, from the chunk's `start` and `end`.
+    fn axis(&self, start: usize, end: usize, size: usize) -> Axis {
+        let halo = self.halo();
+
+        // The window stops at the edges of the map, unless it wraps
+        let wrap = self.params.wraps();
+        let before = if wrap { halo } else { halo.min(start) };
+        let after = if wrap { halo } else { halo.min(size - end) };
+
+        Axis {
+            origin: (start + size - before) % size,
+            len: before + (end - start) + after,
+            start,
+            end,
+            before,
+        }
+    }
+
+    /// The parameters a window around a chunk with `cells` cells is eroded with, on a
+    /// map with dimensions `dims`.
+    fn chunk_params(&self, cells: usize, dims: (usize, usize)) -> ErosionParams {
+        let mut params = self.params.clone();
+
+        // Rain as densely on every chunk as on the whole map
+        params.drop_count =
+            (params.drop_count as u128 * cells as u128 / (dims.0 * dims.1) as u128) as usize;
+
+        // A window isn't a whole map, so it can't wrap onto itself - drops never reach
+        // its edges anyway
+        if params.wraps() {
+            params.boundary = Boundary::Discard;
+        }
+
+        params
+    }
+}
+
+/// One pass of a `ChunkedSimulator` over every chunk.
+#[derive(Debug, Clone, Copy)]
+enum Pass {
+    // Running the pyramid levels
+    Pyramid,
+    // Running the iteration with this number, counting from `0`
+    Iteration(usize),
+}
+
+/// The span of a chunk's window along one side of the map.
+#[derive(Debug, Clone, Copy)]
+struct Axis {
+    // Where the window starts on the map
+    origin: usize,
+    // How many cells long the window is
+    len: usize,
+    // Where the chunk starts on the map
+    start: usize,
+    // Where the chunk ends on the map, exclusive
+    end: usize,
+    // How far the window reaches back past the start of the chunk
+    before: usize,
+}
+
+impl Axis {
+    /// Whether the window's cell at `position` along this side is in the chunk, rather
+    /// than its halo.
+    fn contains(&self, position: usize) -> bool {
+        position >= self.before && position - self.before < self.end - self.start
+    }
+}
+
+/// Make the seed for chunk number `chunk` from the simulation's seed, so every chunk
+/// gets different drops.
+fn chunk_seed(seed: u64, chunk: usize) -> u64 {
+    seed ^ (chunk as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
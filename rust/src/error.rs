@@ -0,0 +1,67 @@
+use std::fmt;
+
+/// Errors that can occur while loading or saving simulation data.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// An EXR file couldn't be decoded or encoded.
+    Exr(exr::error::Error),
+    /// A PNG file couldn't be decoded.
+    Png(png::DecodingError),
+    /// A PNG file couldn't be encoded.
+    PngEncoding(png::EncodingError),
+    /// The contents of a file didn't make sense.
+    Format(String),
+}
+
+/// A `Result` using the crate's `Error` type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Exr(e) => write!(f, "exr error: {e}"),
+            Error::Png(e) => write!(f, "png error: {e}"),
+            Error::PngEncoding(e) => write!(f, "png error: {e}"),
+            Error::Format(message) => write!(f, "invalid data: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Exr(e) => Some(e),
+            Error::Png(e) => Some(e),
+            Error::PngEncoding(e) => Some(e),
+            Error::Format(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<exr::error::Error> for Error {
+    fn from(e: exr::error::Error) -> Self {
+        Error::Exr(e)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(e: png::DecodingError) -> Self {
+        Error::Png(e)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Self {
+        Error::PngEncoding(e)
+    }
+}
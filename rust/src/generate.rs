@@ -0,0 +1,245 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::error::{Error, Result};
+use crate::io::HeightScale;
+
+/// The kinds of fractal noise terrain can be generated from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NoiseKind {
+    /// Fractal Brownian motion - octaves of Perlin noise added together, giving
+    /// rolling hills.
+    #[default]
+    Fbm,
+    /// Ridged multifractal noise - octaves folded into sharp crests, giving
+    /// mountain ranges. Detail builds up on the ridges and stays out of the valleys.
+    Ridged,
+}
+
+impl NoiseKind {
+    /// The names of every kind of noise, in the form accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["fbm", "ridged"];
+}
+
+impl FromStr for NoiseKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "fbm" => Ok(NoiseKind::Fbm),
+            "ridged" => Ok(NoiseKind::Ridged),
+            _ => Err(Error::Format(format!(
+                "unknown noise {s:?}, expected one of: {}",
+                NoiseKind::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for NoiseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseKind::Fbm => write!(f, "fbm"),
+            NoiseKind::Ridged => write!(f, "ridged"),
+        }
+    }
+}
+
+/// How a base terrain is generated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainParams {
+    /// The kind of fractal noise the terrain is made of.
+    pub noise: NoiseKind,
+    /// Seed for the noise - the same seed and parameters always give the same terrain.
+    pub seed: u64,
+    /// The size of the biggest features, in cells.
+    pub scale: f32,
+    /// How many layers of ever finer noise are added together.
+    pub octaves: u32,
+    /// How much finer each octave is than the one before.
+    pub lacunarity: f32,
+    /// How much weaker each octave is than the one before.
+    pub persistence: f32,
+    /// How far, in cells, the terrain is pushed around by another layer of noise
+    /// before it's sampled - `0.0` turns domain warping off.
+    pub warp: f32,
+}
+
+impl Default for TerrainParams {
+    fn default() -> Self {
+        TerrainParams {
+            noise: NoiseKind::Fbm,
+            seed: 0,
+            scale: 256.0,
+            octaves: 8,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            warp: 0.0,
+        }
+    }
+}
+
+/// Generate a heightmap with dimensions `dims`, stretched to go from `0.0` at its
+/// lowest point to `1.0` at its highest.
+///
+/// # Explanation
+///
+/// Every octave samples Perlin noise `lacunarity` times finer and `persistence`
+/// times weaker than the last. With domain warping, two more fBm fields move every
+/// cell before the terrain is sampled there, bending ridges and valleys into the
+/// folded shapes real mountains have.
+pub fn generate(dims: (usize, usize), params: &TerrainParams) -> Vec<f32> {
+    let perlin = Perlin::new(params.seed);
+    let frequency = 1.0 / params.scale.max(f32::EPSILON);
+
+    let mut heightmap: Vec<f32> = (0..dims.0 * dims.1)
+        .into_par_iter()
+        .map(|index| {
+            let mut x = (index % dims.0) as f32 * frequency;
+            let mut y = (index / dims.0) as f32 * frequency;
+
+            if params.warp != 0.0 {
+                // Offsets keep the two warp fields from matching each other or the terrain
+                let warp = params.warp * frequency;
+                let (warp_x, warp_y) = (
+                    fbm(&perlin, x + 17.3, y + 9.2, params),
+                    fbm(&perlin, x + 5.8, y + 31.7, params),
+                );
+                x += warp * warp_x;
+                y += warp * warp_y;
+            }
+
+            match params.noise {
+                NoiseKind::Fbm => fbm(&perlin, x, y, params),
+                NoiseKind::Ridged => ridged(&perlin, x, y, params),
+            }
+        })
+        .collect();
+
+    HeightScale::Normalize { min: 0.0, max: 1.0 }.apply(&mut heightmap);
+
+    heightmap
+}
+
+/// Octaves of Perlin noise added together, each finer and weaker than the last.
+fn fbm(perlin: &Perlin, x: f32, y: f32, params: &TerrainParams) -> f32 {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+
+    for octave in 0..params.octaves {
+        // Shifting every octave stops their lattices lining up at the origin
+        let shift = octave as f32 * 7.31;
+        sum += amplitude * perlin.sample(x * frequency + shift, y * frequency + shift);
+
+        frequency *= params.lacunarity;
+        amplitude *= params.persistence;
+    }
+
+    sum
+}
+
+/// Musgrave's ridged multifractal - every octave is folded into ridges, and weighted
+/// by the octave before it so detail only builds up on the crests.
+fn ridged(perlin: &Perlin, x: f32, y: f32, params: &TerrainParams) -> f32 {
+    const OFFSET: f32 = 1.0;
+    const GAIN: f32 = 2.0;
+
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    let mut weight = 1.0;
+
+    for octave in 0..params.octaves {
+        let shift = octave as f32 * 7.31;
+        let noise = perlin.sample(x * frequency + shift, y * frequency + shift);
+
+        let signal = (OFFSET - noise.abs()).powi(2) * weight;
+        weight = (signal * GAIN).clamp(0.0, 1.0);
+        sum += signal * amplitude;
+
+        frequency *= params.lacunarity;
+        amplitude *= params.persistence;
+    }
+
+    sum
+}
+
+/// Classic 2D Perlin gradient noise, from roughly `-1.0` to `1.0`.
+struct Perlin {
+    // A shuffle of `0..256`, twice over so lookups never need wrapping
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    /// Build the lattice for `seed`.
+    fn new(seed: u64) -> Self {
+        let mut shuffled: Vec<u8> = (0..=255).collect();
+        shuffled.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+
+        let mut permutation = [0; 512];
+        for (index, value) in permutation.iter_mut().enumerate() {
+            *value = shuffled[index % 256];
+        }
+
+        Perlin { permutation }
+    }
+
+    /// The noise at `(x, y)`.
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (cell_x, cell_y) = (x.floor(), y.floor());
+        let (x, y) = (x - cell_x, y - cell_y);
+        let (cell_x, cell_y) = (cell_x as i64 as u8 as usize, cell_y as i64 as u8 as usize);
+
+        let hash = |dx: usize, dy: usize| {
+            let p = &self.permutation;
+            p[p[cell_x + dx] as usize + cell_y + dy]
+        };
+
+        let (u, v) = (fade(x), fade(y));
+        let top = lerp(
+            gradient(hash(0, 0), x, y),
+            gradient(hash(1, 0), x - 1.0, y),
+            u,
+        );
+        let bottom = lerp(
+            gradient(hash(0, 1), x, y - 1.0),
+            gradient(hash(1, 1), x - 1.0, y - 1.0),
+            u,
+        );
+
+        // Scaled so the steepest corner-to-corner ramp reaches about 1.0
+        lerp(top, bottom, v) * std::f32::consts::SQRT_2
+    }
+}
+
+/// The dot product of one of 8 unit gradients, picked by `hash`, with `(x, y)`.
+fn gradient(hash: u8, x: f32, y: f32) -> f32 {
+    const DIAGONAL: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    match hash & 7 {
+        0 => x,
+        1 => -x,
+        2 => y,
+        3 => -y,
+        4 => (x + y) * DIAGONAL,
+        5 => (x - y) * DIAGONAL,
+        6 => (-x + y) * DIAGONAL,
+        _ => (-x - y) * DIAGONAL,
+    }
+}
+
+/// Perlin's quintic ease curve, so the noise is smooth across cell edges.
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Blend from `a` to `b` by `t`.
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
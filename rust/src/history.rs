@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+
+/// How hard deltas are compressed - low, since one is made every iteration.
+const COMPRESSION_LEVEL: u8 = 1;
+
+/// The cells one erosion pass changed, compressed.
+///
+/// # Explanation
+///
+/// Every changed cell is stored as the gap since the last changed cell and the XOR of
+/// its height's bits before and after, both as variable-length integers. Nearby floats
+/// share their top bits, so small changes take fewer bytes, and XORing the same bits back
+/// in turns `after` into `before` and `before` into `after` exactly. The whole list is
+/// then deflated, which mostly squeezes the gaps - cells untouched by a pass cost nothing.
+#[derive(Debug, Clone)]
+pub struct Delta {
+    // The deflated list of gaps and XORed bits
+    data: Vec<u8>,
+    // How many cells the heightmap had
+    cells: usize,
+    // How many cells changed
+    changes: usize,
+}
+
+impl Delta {
+    /// Work out the delta from `before` to `after`, which need to be the same length.
+    pub fn between(before: &[f32], after: &[f32]) -> Self {
+        Delta::advance(&mut before.to_vec(), after)
+    }
+
+    /// Work out the delta from `snapshot` to `after`, which need to be the same length,
+    /// and bring `snapshot` up to date with `after` along the way.
+    ///
+    /// Only the cells that changed are written to, so keeping one snapshot and advancing
+    /// it every pass is much cheaper than copying the whole map each time.
+    pub fn advance(snapshot: &mut [f32], after: &[f32]) -> Self {
+        assert_eq!(snapshot.len(), after.len(), "maps are different sizes");
+
+        let mut bytes = Vec::new();
+        let mut last = 0;
+        let mut changes = 0;
+
+        for (index, (before, after)) in snapshot.iter_mut().zip(after).enumerate() {
+            let bits = before.to_bits() ^ after.to_bits();
+            if bits != 0 {
+                write_varint(&mut bytes, (index - last) as u64);
+                write_varint(&mut bytes, bits as u64);
+                last = index;
+                changes += 1;
+                *before = *after;
+            }
+        }
+
+        Delta {
+            data: compress_to_vec(&bytes, COMPRESSION_LEVEL),
+            cells: snapshot.len(),
+            changes,
+        }
+    }
+
+    /// Apply the delta to `heightmap` - going from `before` to `after` or from `after`
+    /// back to `before`, since it works both ways.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `heightmap` isn't the size the delta was made for.
+    pub fn apply(&self, heightmap: &mut [f32]) {
+        assert_eq!(heightmap.len(), self.cells, "heightmap is the wrong size");
+
+        let bytes = decompress_to_vec(&self.data).expect("history deltas are always valid");
+        let mut bytes = bytes.as_slice();
+        let mut index = 0;
+
+        while !bytes.is_empty() {
+            index += read_varint(&mut bytes) as usize;
+            let bits = read_varint(&mut bytes) as u32;
+            heightmap[index] = f32::from_bits(heightmap[index].to_bits() ^ bits);
+        }
+    }
+
+    /// How many cells the delta changes.
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    /// How many bytes the delta takes up.
+    pub fn size(&self) -> usize {
+        self.data.len() + std::mem::size_of::<Self>()
+    }
+}
+
+/// What one erosion pass changed in each of the maps it works on - the heightmap, and
+/// any others that wear down along with it, like a hardness map.
+#[derive(Debug, Clone, Default)]
+pub struct Pass {
+    // A delta for every map, in the order they were given
+    deltas: Vec<Delta>,
+}
+
+impl Pass {
+    /// Work out what changed in every map, given as `(before, after)` pairs that each
+    /// need to be the same length. A map there isn't can be given as two empty slices.
+    pub fn between(maps: &[(&[f32], &[f32])]) -> Self {
+        Pass {
+            deltas: maps
+                .iter()
+                .map(|(before, after)| Delta::between(before, after))
+                .collect(),
+        }
+    }
+
+    /// Work out what changed in every map like `between`, from `(snapshot, after)` pairs,
+    /// bringing every snapshot up to date with `after` along the way like
+    /// `Delta::advance`.
+    pub fn advance(maps: &mut [(&mut [f32], &[f32])]) -> Self {
+        Pass {
+            deltas: maps
+                .iter_mut()
+                .map(|(snapshot, after)| Delta::advance(snapshot, after))
+                .collect(),
+        }
+    }
+
+    /// Apply the pass to `maps`, in the order they were given when it was made, going either
+    /// way like `Delta::apply`.
+    ///
+    /// A map that isn't the size it was when the pass was made has been replaced since, so
+    /// it's left as it is.
+    pub fn apply(&self, maps: &mut [&mut [f32]]) {
+        for (delta, map) in self.deltas.iter().zip(maps) {
+            if map.len() == delta.cells {
+                delta.apply(map);
+            }
+        }
+    }
+
+    /// How many cells the pass changes, over all of the maps.
+    pub fn changes(&self) -> usize {
+        self.deltas.iter().map(Delta::changes).sum()
+    }
+
+    /// How many bytes the pass takes up.
+    pub fn size(&self) -> usize {
+        self.deltas.iter().map(Delta::size).sum::<usize>() + std::mem::size_of::<Self>()
+    }
+}
+
+/// A bounded history of the changes made to a heightmap and the maps that go with it, to
+/// undo and redo them.
+///
+/// Every pass is kept as a `Pass`, and the oldest are forgotten once the history takes up
+/// more than its capacity in bytes. Recording a new pass forgets everything that could be
+/// redone.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    // The passes that can be undone, oldest first
+    undo: VecDeque<Pass>,
+    // The passes that can be redone, the next one last
+    redo: Vec<Pass>,
+    // How many bytes the history can take up
+    capacity: usize,
+    // How many bytes the history takes up
+    size: usize,
+}
+
+impl History {
+    /// Create an empty history that takes up at most `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Record a pass. Working it out with `Pass::between` or `Pass::advance` is the slow
+    /// part, so a history shared between threads is best only locked once that's done.
+    ///
+    /// Returns whether it was kept - a pass that changed nothing isn't, and neither is one
+    /// bigger than the whole capacity, in which case nothing before it can be undone either.
+    pub fn record(&mut self, pass: Pass) -> bool {
+        if pass.changes() == 0 {
+            return false;
+        }
+
+        self.size -= self.redo.drain(..).map(|pass| pass.size()).sum::<usize>();
+        if pass.size() > self.capacity {
+            self.clear();
+            return false;
+        }
+
+        self.size += pass.size();
+        self.undo.push_back(pass);
+        self.shrink();
+
+        true
+    }
+
+    /// Undo the last pass made to `maps`, returning whether there was one.
+    pub fn undo(&mut self, maps: &mut [&mut [f32]]) -> bool {
+        let Some(pass) = self.undo.pop_back() else {
+            return false;
+        };
+
+        pass.apply(maps);
+        self.redo.push(pass);
+
+        true
+    }
+
+    /// Redo the last pass undone on `maps`, returning whether there was one.
+    pub fn redo(&mut self, maps: &mut [&mut [f32]]) -> bool {
+        let Some(pass) = self.redo.pop() else {
+            return false;
+        };
+
+        pass.apply(maps);
+        self.undo.push_back(pass);
+
+        true
+    }
+
+    /// Forget every pass.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.size = 0;
+    }
+
+    /// Change how many bytes the history can take up, forgetting the oldest passes if it
+    /// takes up more than that.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    /// How many bytes the history can take up.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many bytes the history takes up.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// How many passes can be undone.
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// How many passes can be redone.
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Forget passes until the history fits in its capacity - the oldest undo first, then
+    /// the furthest redo.
+    fn shrink(&mut self) {
+        while self.size > self.capacity {
+            let pass = match self.undo.pop_front() {
+                Some(pass) => pass,
+                None if !self.redo.is_empty() => self.redo.remove(0),
+                None => break,
+            };
+            self.size -= pass.size();
+        }
+    }
+}
+
+/// Write `value` 7 bits at a time, lowest first, with the top bit set on every byte but
+/// the last.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Read a value written by `write_varint` off the front of `bytes`.
+fn read_varint(bytes: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+
+    while let Some((&byte, rest)) = bytes.split_first() {
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    value
+}
@@ -0,0 +1,580 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use exr::prelude::{
+    read_first_flat_layer_from_file, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer,
+    LayerAttributes, LineOrder, SpecificChannels, Vec2, WritableImage,
+};
+
+use crate::error::{Error, Result};
+use crate::layers::Strata;
+use crate::tiles::Window;
+
+/// Read a heightmap in whichever format its extension says - raw `.f32`, `.png`,
+/// `.r16`/`.raw` or `.asc` - falling back to EXR for anything else.
+///
+/// Raw `.f32` files need `dims`, and `.r16`/`.raw` files need them too unless
+/// they're square - every other format stores its own.
+///
+/// # Returns
+///
+/// The heightmap and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_heightmap(
+    path: impl AsRef<Path>,
+    dims: Option<(usize, usize)>,
+) -> Result<(Vec<f32>, (usize, usize))> {
+    let path = path.as_ref();
+
+    match extension(path).as_deref() {
+        Some("f32") => {
+            let dims = dims.ok_or_else(|| {
+                Error::Format("a raw .f32 heightmap needs its dimensions".to_string())
+            })?;
+            read_raw(path, dims).map(|heightmap| (heightmap, dims))
+        }
+        Some("png") => read_png(path),
+        Some("r16" | "raw") => read_r16(path, dims),
+        Some("asc") => read_ascii_grid(path),
+        _ => read_exr(path),
+    }
+}
+
+/// Write a heightmap as raw floats if `path` ends in `.f32`, and as an EXR otherwise.
+pub fn write_heightmap(
+    path: impl AsRef<Path>,
+    heightmap: &[f32],
+    dims: (usize, usize),
+) -> Result<()> {
+    let path = path.as_ref();
+
+    match extension(path).as_deref() {
+        Some("f32") => write_raw(path, heightmap, dims),
+        _ => write_exr(path, heightmap, dims),
+    }
+}
+
+/// Whether `read_heightmap` reads `path` as an EXR, which is the only format with
+/// channels other than the heights.
+pub fn is_exr(path: impl AsRef<Path>) -> bool {
+    !matches!(
+        extension(path.as_ref()).as_deref(),
+        Some("f32" | "png" | "r16" | "raw" | "asc")
+    )
+}
+
+/// The lowercase extension of `path`, if it has one.
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Read a heightmap from an EXR file, like `terrain_texture.exr`.
+///
+/// The height is taken from the `R` channel, falling back to `Y` for greyscale
+/// images and then to whatever channel comes first.
+///
+/// # Returns
+///
+/// The heightmap and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_exr(path: impl AsRef<Path>) -> Result<(Vec<f32>, (usize, usize))> {
+    let image = read_first_flat_layer_from_file(path)?;
+    let layer = image.layer_data;
+    let dims = (layer.size.0, layer.size.1);
+
+    let channels = &layer.channel_data.list;
+    let channel = channels
+        .iter()
+        .find(|channel| channel.name.eq("R"))
+        .or_else(|| channels.iter().find(|channel| channel.name.eq("Y")))
+        .or_else(|| channels.first())
+        .ok_or_else(|| Error::Format("the EXR file has no channels".to_string()))?;
+
+    let heightmap: Vec<f32> = channel.sample_data.values_as_f32().collect();
+
+    Ok((heightmap, dims))
+}
+
+/// Read a single named channel from an EXR file - like `G` for a hardness map
+/// stored alongside the height.
+///
+/// # Returns
+///
+/// The channel's values and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_exr_channel(path: impl AsRef<Path>, name: &str) -> Result<(Vec<f32>, (usize, usize))> {
+    let image = read_first_flat_layer_from_file(path)?;
+    let layer = image.layer_data;
+    let dims = (layer.size.0, layer.size.1);
+
+    let channel = layer
+        .channel_data
+        .list
+        .iter()
+        .find(|channel| channel.name.eq(name))
+        .ok_or_else(|| Error::Format(format!("the EXR file has no {name:?} channel")))?;
+
+    let values: Vec<f32> = channel.sample_data.values_as_f32().collect();
+
+    Ok((values, dims))
+}
+
+/// Read a heightmap from a PNG file, like a 16-bit greyscale export.
+///
+/// The height is taken from the first channel - grey, or red for colour images -
+/// and goes from `0.0` for black to `1.0` for white whatever the bit depth.
+///
+/// # Returns
+///
+/// The heightmap and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_png(path: impl AsRef<Path>) -> Result<(Vec<f32>, (usize, usize))> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palettes and bit depths under 8 are expanded to plain 8-bit samples
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let dims = (info.width as usize, info.height as usize);
+
+    let samples = info.color_type.samples();
+    let bytes = &buffer[..info.buffer_size()];
+
+    let heightmap: Vec<f32> = match info.bit_depth {
+        png::BitDepth::Sixteen => bytes
+            .chunks_exact(2 * samples)
+            .map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+        _ => bytes
+            .chunks_exact(samples)
+            .map(|pixel| pixel[0] as f32 / u8::MAX as f32)
+            .collect(),
+    };
+
+    Ok((heightmap, dims))
+}
+
+/// Read a heightmap from a `.r16` or `.raw` file, as exported by World Machine or
+/// Unity - unsigned little-endian 16-bit heights, row by row with no header.
+///
+/// Heights go from `0.0` to `1.0`. The files don't store their size, so without
+/// `dims` the heightmap is taken to be square, as these tools always export it.
+///
+/// # Returns
+///
+/// The heightmap and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_r16(
+    path: impl AsRef<Path>,
+    dims: Option<(usize, usize)>,
+) -> Result<(Vec<f32>, (usize, usize))> {
+    let bytes = std::fs::read(path)?;
+    let cells = bytes.len() / 2;
+
+    let dims = match dims {
+        Some(dims) => dims,
+        None => {
+            let side = (cells as f64).sqrt().round() as usize;
+            if bytes.len() % 2 != 0 || side * side != cells {
+                return Err(Error::Format(format!(
+                    "the 16-bit raw file is {} bytes, which isn't a square heightmap - give its dimensions",
+                    bytes.len()
+                )));
+            }
+            (side, side)
+        }
+    };
+
+    if bytes.len() != dims.0 * dims.1 * 2 {
+        return Err(Error::Format(format!(
+            "the 16-bit raw file is {} bytes, but a {}x{} heightmap takes {}",
+            bytes.len(),
+            dims.0,
+            dims.1,
+            dims.0 * dims.1 * 2
+        )));
+    }
+
+    let heightmap: Vec<f32> = bytes
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+        .collect();
+
+    Ok((heightmap, dims))
+}
+
+/// Read a heightmap from an ESRI ASCII grid (`.asc`).
+///
+/// Heights are kept in the grid's own units, with the northernmost row first.
+/// Cells holding the `NODATA_value` are filled in with the lowest height in the
+/// grid, so they don't turn into deep pits.
+///
+/// # Returns
+///
+/// The heightmap and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn read_ascii_grid(path: impl AsRef<Path>) -> Result<(Vec<f32>, (usize, usize))> {
+    let text = std::fs::read_to_string(path)?;
+    let mut tokens = text.split_whitespace().peekable();
+
+    let mut columns = None;
+    let mut rows = None;
+    let mut nodata = None;
+
+    // The header is a list of keys and values, ending where the heights start
+    while let Some(key) = tokens.next_if(|token| token.parse::<f32>().is_err()) {
+        let value = tokens
+            .next()
+            .ok_or_else(|| Error::Format(format!("the ASCII grid has no value for {key:?}")))?;
+
+        match key.to_ascii_lowercase().as_str() {
+            "ncols" => columns = Some(parse_grid_value::<usize>(key, value)?),
+            "nrows" => rows = Some(parse_grid_value::<usize>(key, value)?),
+            "nodata_value" => nodata = Some(parse_grid_value::<f32>(key, value)?),
+            // The position and cell size don't change the heights
+            "xllcorner" | "yllcorner" | "xllcenter" | "yllcenter" | "cellsize" => {}
+            _ => {
+                return Err(Error::Format(format!(
+                    "the ASCII grid has an unknown header {key:?}"
+                )))
+            }
+        }
+    }
+
+    let (Some(columns), Some(rows)) = (columns, rows) else {
+        return Err(Error::Format(
+            "the ASCII grid header needs both ncols and nrows".to_string(),
+        ));
+    };
+    let dims = (columns, rows);
+
+    let mut heightmap: Vec<f32> = tokens
+        .map(|token| parse_grid_value::<f32>("height", token))
+        .collect::<Result<_>>()?;
+
+    if heightmap.len() != columns * rows {
+        return Err(Error::Format(format!(
+            "the ASCII grid has {} heights, but a {columns}x{rows} grid takes {}",
+            heightmap.len(),
+            columns * rows
+        )));
+    }
+
+    if let Some(nodata) = nodata {
+        let lowest = heightmap
+            .iter()
+            .copied()
+            .filter(|&height| height != nodata)
+            .fold(f32::INFINITY, f32::min);
+        let lowest = if lowest.is_finite() { lowest } else { 0.0 };
+
+        heightmap
+            .iter_mut()
+            .filter(|height| **height == nodata)
+            .for_each(|height| *height = lowest);
+    }
+
+    Ok((heightmap, dims))
+}
+
+/// Parses a single value from an ASCII grid, naming what it was for if it's not a number.
+fn parse_grid_value<T: std::str::FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::Format(format!("the ASCII grid has an invalid {name}: {value:?}")))
+}
+
+/// How the heights read from a file are turned into the heights the simulation
+/// works with - the importers give PNGs and 16-bit raw files heights from `0.0`
+/// to `1.0`, and ASCII grids heights in their own units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeightScale {
+    /// Multiply every height by this.
+    Scale(f32),
+    /// Stretch the heights so the lowest becomes `min` and the highest `max`.
+    Normalize { min: f32, max: f32 },
+}
+
+impl Default for HeightScale {
+    /// Keep the heights as they were read.
+    fn default() -> Self {
+        HeightScale::Scale(1.0)
+    }
+}
+
+impl HeightScale {
+    /// Rescale every height in `heightmap`.
+    ///
+    /// Normalising a completely flat heightmap puts every cell at `min`.
+    pub fn apply(self, heightmap: &mut [f32]) {
+        match self {
+            HeightScale::Scale(scale) => heightmap.iter_mut().for_each(|height| *height *= scale),
+            HeightScale::Normalize { min, max } => {
+                let (lowest, highest) = heightmap.iter().fold(
+                    (f32::INFINITY, f32::NEG_INFINITY),
+                    |(lowest, highest), &height| (lowest.min(height), highest.max(height)),
+                );
+
+                let range = highest - lowest;
+                let scale = if range > 0.0 {
+                    (max - min) / range
+                } else {
+                    0.0
+                };
+
+                heightmap
+                    .iter_mut()
+                    .for_each(|height| *height = min + (*height - lowest) * scale);
+            }
+        }
+    }
+}
+
+/// Write a heightmap to an RGB EXR file, with the height in every channel.
+///
+/// Blocks are always written top to bottom, so the same heightmap always gives
+/// a byte-identical file.
+pub fn write_exr(path: impl AsRef<Path>, heightmap: &[f32], dims: (usize, usize)) -> Result<()> {
+    let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+        let r = heightmap[y * dims.0 + x];
+
+        (r, r, r)
+    });
+
+    let encoding = Encoding {
+        line_order: LineOrder::Increasing,
+        ..Encoding::default()
+    };
+
+    Image::from_encoded_channels(dims, encoding, channels)
+        .write()
+        .to_file(path)?;
+
+    Ok(())
+}
+
+/// Write a normal map, with its colours from `0.0` to `1.0` like those from
+/// `SurfaceMaps::normal_colours`.
+///
+/// Files ending in `.png` are written as 16-bit RGB PNGs, which most engines can
+/// use directly, and anything else as an RGB EXR.
+pub fn write_normal_map(
+    path: impl AsRef<Path>,
+    colours: &[[f32; 3]],
+    dims: (usize, usize),
+) -> Result<()> {
+    let path = path.as_ref();
+
+    if extension(path).as_deref() != Some("png") {
+        let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+            let [r, g, b] = colours[y * dims.0 + x];
+
+            (r, g, b)
+        });
+
+        let encoding = Encoding {
+            line_order: LineOrder::Increasing,
+            ..Encoding::default()
+        };
+
+        Image::from_encoded_channels(dims, encoding, channels)
+            .write()
+            .to_file(path)?;
+
+        return Ok(());
+    }
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        dims.0 as u32,
+        dims.1 as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Sixteen);
+
+    // 16-bit PNG samples are big-endian
+    let bytes: Vec<u8> = colours
+        .iter()
+        .flatten()
+        .flat_map(|value| ((value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16).to_be_bytes())
+        .collect();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&bytes)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Write any number of named single-channel maps to one EXR file, like the
+/// thickness of every material layer.
+///
+/// Channels are stored sorted by name, as EXR requires, and blocks are always
+/// written top to bottom so the same maps always give a byte-identical file.
+pub fn write_exr_channels(
+    path: impl AsRef<Path>,
+    channels: &[(&str, &[f32])],
+    dims: (usize, usize),
+) -> Result<()> {
+    let channels: Vec<_> = channels
+        .iter()
+        .map(|(name, values)| AnyChannel::new(*name, FlatSamples::F32(values.to_vec())))
+        .collect();
+
+    let encoding = Encoding {
+        line_order: LineOrder::Increasing,
+        ..Encoding::default()
+    };
+
+    let layer = Layer::new(
+        dims,
+        LayerAttributes::default(),
+        encoding,
+        AnyChannels::sort(channels.into()),
+    );
+
+    Image::from_layer(layer).write().to_file(path)?;
+
+    Ok(())
+}
+
+/// The channel `write_strata` stores the exposed layer in, which no layer can be named.
+pub const EXPOSED_CHANNEL: &str = "exposed";
+
+/// Write the material layers to an EXR file, to texture the strata with.
+///
+/// Every layer but the bottom one gets a channel named after it holding its
+/// thickness, and the `exposed` channel holds the index of the layer showing
+/// at the surface (`0` for the top layer).
+pub fn write_strata(path: impl AsRef<Path>, strata: &Strata, dims: (usize, usize)) -> Result<()> {
+    let layers = strata.layers();
+    let thickness: Vec<Vec<f32>> = (0..layers.len() - 1)
+        .filter_map(|layer| strata.thickness(layer))
+        .collect();
+    let exposed: Vec<f32> = strata
+        .exposed()
+        .into_iter()
+        .map(|layer| layer as f32)
+        .collect();
+
+    let mut channels: Vec<(&str, &[f32])> = layers
+        .iter()
+        .zip(&thickness)
+        .map(|(layer, thickness)| (layer.name.as_str(), thickness.as_slice()))
+        .collect();
+    channels.push((EXPOSED_CHANNEL, &exposed));
+
+    write_exr_channels(path, &channels, dims)
+}
+
+/// A heightmap stored in a file as raw little-endian `f32`s, row by row with no
+/// header, that's read and written a window at a time - so it never has to fit
+/// in memory all at once.
+#[derive(Debug)]
+pub struct RawMap {
+    // The file the heights are stored in
+    file: File,
+    // The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`
+    dims: (usize, usize),
+}
+
+impl RawMap {
+    /// Open an existing raw heightmap with dimensions `dims` for reading.
+    ///
+    /// Fails if the file isn't exactly the right size for those dimensions.
+    pub fn open(path: impl AsRef<Path>, dims: (usize, usize)) -> Result<Self> {
+        let file = File::open(path)?;
+
+        let size = file.metadata()?.len();
+        let expected = raw_size(dims);
+        if size != expected {
+            return Err(Error::Format(format!(
+                "the raw file is {size} bytes, but a {}x{} heightmap takes {expected}",
+                dims.0, dims.1
+            )));
+        }
+
+        Ok(RawMap { file, dims })
+    }
+
+    /// Create a raw heightmap with dimensions `dims` filled with `0.0`, replacing
+    /// any file already at `path`.
+    pub fn create(path: impl AsRef<Path>, dims: (usize, usize)) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(raw_size(dims))?;
+
+        Ok(RawMap { file, dims })
+    }
+
+    /// The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`.
+    pub fn dims(&self) -> (usize, usize) {
+        self.dims
+    }
+
+    /// Read the part of the heightmap covered by `window`.
+    pub fn read_window(&mut self, window: &Window) -> Result<Vec<f32>> {
+        let mut values = vec![0.0; window.cells()];
+        let mut bytes = Vec::new();
+
+        for (start, offset, len) in window.spans(self.dims) {
+            bytes.resize(len * 4, 0);
+            self.file.seek(SeekFrom::Start(start as u64 * 4))?;
+            self.file.read_exact(&mut bytes)?;
+
+            for (value, bytes) in values[offset..offset + len]
+                .iter_mut()
+                .zip(bytes.chunks_exact(4))
+            {
+                *value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Write `values` over the part of the heightmap covered by `window`.
+    pub fn write_window(&mut self, window: &Window, values: &[f32]) -> Result<()> {
+        let mut bytes = Vec::new();
+
+        for (start, offset, len) in window.spans(self.dims) {
+            bytes.clear();
+            bytes.extend(
+                values[offset..offset + len]
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes()),
+            );
+
+            self.file.seek(SeekFrom::Start(start as u64 * 4))?;
+            self.file.write_all(&bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a whole heightmap with dimensions `dims` from a raw file - see `RawMap`.
+pub fn read_raw(path: impl AsRef<Path>, dims: (usize, usize)) -> Result<Vec<f32>> {
+    let whole = Window {
+        origin: (0, 0),
+        dims,
+    };
+
+    RawMap::open(path, dims)?.read_window(&whole)
+}
+
+/// Write a whole heightmap to a raw file - see `RawMap`.
+pub fn write_raw(path: impl AsRef<Path>, heightmap: &[f32], dims: (usize, usize)) -> Result<()> {
+    let whole = Window {
+        origin: (0, 0),
+        dims,
+    };
+
+    RawMap::create(path, dims)?.write_window(&whole, heightmap)
+}
+
+/// The size in bytes of a raw heightmap with dimensions `dims`.
+fn raw_size(dims: (usize, usize)) -> u64 {
+    dims.0 as u64 * dims.1 as u64 * 4
+}
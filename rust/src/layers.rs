@@ -0,0 +1,165 @@
+use rayon::prelude::*;
+
+use crate::params::LayerParams;
+
+/// The material the terrain is made of, as a column of layers under every cell.
+///
+/// Layers are ordered from the top down. The top one is where sediment is
+/// deposited, and the bottom one (usually bedrock) goes down forever - so only
+/// the thickness of the layers above it is stored.
+///
+/// The heightmap stays the source of truth for the height; this just follows the
+/// changes made to it. Erosion strips material off the top, digging into the next
+/// layer once one runs out, while deposition always adds to the top layer.
+#[derive(Debug, Clone)]
+pub struct Strata {
+    // The layers this was built from, top to bottom
+    layers: Vec<LayerParams>,
+    // The thickness of every layer but the bottom one, stored cell by cell
+    thickness: Vec<f32>,
+    // How many cells there are
+    cells: usize,
+}
+
+impl Strata {
+    /// Create a new `Strata` covering `cells` cells, with every layer starting at its
+    /// `thickness` everywhere.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no layers.
+    pub fn new(layers: &[LayerParams], cells: usize) -> Self {
+        assert!(!layers.is_empty(), "strata need at least one layer");
+
+        let column: Vec<f32> = layers[..layers.len() - 1]
+            .iter()
+            .map(|layer| layer.thickness.max(0.0))
+            .collect();
+
+        Strata {
+            layers: layers.to_vec(),
+            thickness: column.repeat(cells),
+            cells,
+        }
+    }
+
+    /// Rebuild a `Strata` covering `cells` cells from its layers and the thickness of every
+    /// layer but the bottom one, stored cell by cell as `packed_thickness` gives them.
+    ///
+    /// # Returns
+    ///
+    /// `None` if there are no layers, or there isn't a thickness for every stored layer
+    /// of every cell.
+    pub fn from_packed(layers: &[LayerParams], thickness: Vec<f32>, cells: usize) -> Option<Self> {
+        let stored = layers.len().checked_sub(1)?;
+        if thickness.len() != cells * stored {
+            return None;
+        }
+
+        Some(Strata {
+            layers: layers.to_vec(),
+            thickness,
+            cells,
+        })
+    }
+
+    /// The thickness of every layer but the bottom one, stored cell by cell.
+    pub fn packed_thickness(&self) -> &[f32] {
+        &self.thickness
+    }
+
+    /// The thickness of every layer but the bottom one, to change in place.
+    pub fn packed_thickness_mut(&mut self) -> &mut [f32] {
+        &mut self.thickness
+    }
+
+    /// The layers, from the top down.
+    pub fn layers(&self) -> &[LayerParams] {
+        &self.layers
+    }
+
+    /// The thickness of a layer in every cell, or `None` for the bottom layer.
+    pub fn thickness(&self, layer: usize) -> Option<Vec<f32>> {
+        let stored = self.layers.len() - 1;
+        if layer >= stored {
+            return None;
+        }
+
+        Some(
+            self.thickness
+                .iter()
+                .skip(layer)
+                .step_by(stored)
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// The index of the layer showing at the surface of every cell.
+    pub fn exposed(&self) -> Vec<usize> {
+        self.columns().map(exposed_layer).collect()
+    }
+
+    /// The resistance of every cell to erosion, from `0.0` (soft) to `1.0` (can't be eroded),
+    /// taken from the erodibility of whichever layer is showing.
+    pub fn resistance(&self) -> Vec<f32> {
+        self.columns()
+            .map(|column| {
+                let erodibility = self.layers[exposed_layer(column)].erodibility;
+
+                1.0 - erodibility.clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+
+    /// Follow the changes made to the heightmap since it was `before`.
+    ///
+    /// # Explanation
+    ///
+    /// Material lost from a cell is taken off the top layer until it runs out, then
+    /// the next one and so on - whatever is left comes out of the bottom layer.
+    /// Material gained is all added to the top layer as sediment.
+    pub fn apply_changes(&mut self, before: &[f32], after: &[f32]) {
+        let stored = self.layers.len() - 1;
+        if stored == 0 {
+            return;
+        }
+
+        self.thickness
+            .par_chunks_mut(stored)
+            .zip(before.par_iter().zip(after))
+            .for_each(|(column, (before, after))| {
+                let mut change = after - before;
+
+                if change >= 0.0 {
+                    column[0] += change;
+                    return;
+                }
+
+                for thickness in column.iter_mut() {
+                    let removed = thickness.min(-change);
+                    *thickness -= removed;
+                    change += removed;
+
+                    if change >= 0.0 {
+                        break;
+                    }
+                }
+            });
+    }
+
+    /// Iterate over the stored layer thicknesses of every cell.
+    fn columns(&self) -> impl Iterator<Item = &[f32]> {
+        let stored = self.layers.len() - 1;
+
+        (0..self.cells).map(move |cell| &self.thickness[cell * stored..(cell + 1) * stored])
+    }
+}
+
+/// The index of the top layer with anything left in it, given the stored layers of a cell.
+fn exposed_layer(column: &[f32]) -> usize {
+    column
+        .iter()
+        .position(|&thickness| thickness > 0.0)
+        .unwrap_or(column.len())
+}
@@ -1,23 +1,70 @@
 #![feature(f16)]
 
 use godot::prelude::*;
+use precipitation::RainSampler;
 use raindrop::Raindrop;
+use rand::Rng;
 
+pub mod brush;
+pub mod changes;
+pub mod checkpoint;
+pub mod chunked;
+pub mod error;
+pub mod generate;
+pub mod history;
+pub mod io;
+pub mod layers;
+pub mod mesh;
+pub mod params;
+pub mod precipitation;
+pub mod pyramid;
 pub mod raindrop;
+pub mod shallow_water;
+pub mod simulator;
+pub mod surface;
 pub mod terrain_mesh;
+pub mod thermal;
+pub mod tiles;
 
 struct ErosionExtension;
 
 #[gdextension]
 unsafe impl ExtensionLibrary for ErosionExtension {}
 
-/// Creates Raindrops at random points on the terrain
-fn create_raindrops(num: usize, mass: f32, dims: (usize, usize)) -> Vec<Raindrop> {
+/// Creates Raindrops at random points on the terrain, drawing positions from `rng`.
+///
+/// Without a `rain` sampler every cell is equally likely - otherwise drops fall where it
+/// says, which should never be on the last row or column. There are no drops if there's
+/// nowhere for one to start, as on a map that doesn't wrap and is a single cell across -
+/// see `ErosionParams::check_dims`.
+fn create_raindrops(
+    num: usize,
+    mass: f32,
+    dims: (usize, usize),
+    wrap: bool,
+    rain: Option<&RainSampler>,
+    rng: &mut impl Rng,
+) -> Vec<Raindrop> {
+    // Raindrops can start anywhere on a wrapping map, but otherwise not on the last row or column
+    let spawnable = if wrap {
+        dims
+    } else {
+        (dims.0.saturating_sub(1), dims.1.saturating_sub(1))
+    };
+    if rain.is_none() && (spawnable.0 == 0 || spawnable.1 == 0) {
+        return Vec::new();
+    }
+
     let mut drops: Vec<Raindrop> = Vec::with_capacity(num);
 
     while drops.len() < num {
-        let x = rand::random::<usize>() % (dims.0 - 1);
-        let y = rand::random::<usize>() % (dims.1 - 1);
+        let (x, y) = match rain {
+            Some(rain) => {
+                let index = rain.sample(rng);
+                (index % dims.0, index / dims.0)
+            }
+            None => (rng.gen_range(0..spawnable.0), rng.gen_range(0..spawnable.1)),
+        };
 
         drops.push(Raindrop::new(mass, x as f32, y as f32));
     }
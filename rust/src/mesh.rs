@@ -0,0 +1,327 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::error::{Error, Result};
+
+/// How a heightmap is turned into a `TriangleMesh`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshOptions {
+    /// The horizontal distance between neighbouring cells.
+    pub spacing: f32,
+    /// Every height is multiplied by this.
+    pub height_scale: f32,
+    /// Only every this many cells gets a vertex, to decimate big maps - the last row
+    /// and column are always kept so the mesh covers the whole map.
+    pub step: usize,
+}
+
+impl Default for MeshOptions {
+    fn default() -> Self {
+        MeshOptions {
+            spacing: 1.0,
+            height_scale: 1.0,
+            step: 1,
+        }
+    }
+}
+
+/// A triangulated heightmap, with a normal and UV for every vertex.
+///
+/// The mesh is `y`-up, with the heightmap's `x` along `+x` and its `y` along `+z`,
+/// and triangles wind counter-clockwise seen from above. UVs go from `(0, 0)` at the
+/// first cell to `(1, 1)` at the last, top to bottom like the image.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TriangleMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    /// Three indices into the vertices per triangle.
+    pub indices: Vec<u32>,
+}
+
+impl TriangleMesh {
+    /// Triangulate a heightmap with dimensions `dims`, two triangles to every quad
+    /// of vertices.
+    ///
+    /// # Returns
+    ///
+    /// The mesh, or an `Error::Format` if the heightmap has no cells.
+    pub fn from_heightmap(
+        heightmap: &[f32],
+        dims: (usize, usize),
+        options: &MeshOptions,
+    ) -> Result<Self> {
+        if dims.0 == 0 || dims.1 == 0 {
+            return Err(Error::Format(format!(
+                "can't make a mesh of an empty {}x{} heightmap",
+                dims.0, dims.1
+            )));
+        }
+
+        let columns = sample_points(dims.0, options.step);
+        let rows = sample_points(dims.1, options.step);
+        let width = columns.len();
+
+        let height =
+            |x: usize, y: usize| heightmap[rows[y] * dims.0 + columns[x]] * options.height_scale;
+        let position = |x: usize, y: usize| {
+            [
+                columns[x] as f32 * options.spacing,
+                height(x, y),
+                rows[y] as f32 * options.spacing,
+            ]
+        };
+
+        let mut mesh = TriangleMesh::default();
+
+        for y in 0..rows.len() {
+            for x in 0..width {
+                mesh.positions.push(position(x, y));
+
+                // The slope across the neighbouring vertices, one-sided at the edges
+                let (left, right) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                let (up, down) = (y.saturating_sub(1), (y + 1).min(rows.len() - 1));
+                let dx = (height(right, y) - height(left, y))
+                    / ((columns[right] - columns[left]).max(1) as f32 * options.spacing);
+                let dz = (height(x, down) - height(x, up))
+                    / ((rows[down] - rows[up]).max(1) as f32 * options.spacing);
+                mesh.normals.push(normalize([-dx, 1.0, -dz]));
+
+                mesh.uvs.push([
+                    columns[x] as f32 / (dims.0 - 1).max(1) as f32,
+                    rows[y] as f32 / (dims.1 - 1).max(1) as f32,
+                ]);
+            }
+        }
+
+        for y in 0..rows.len().saturating_sub(1) {
+            for x in 0..width.saturating_sub(1) {
+                let top_left = (y * width + x) as u32;
+                let top_right = top_left + 1;
+                let bottom_left = top_left + width as u32;
+                let bottom_right = bottom_left + 1;
+
+                mesh.indices.extend([
+                    top_left,
+                    bottom_left,
+                    top_right,
+                    top_right,
+                    bottom_left,
+                    bottom_right,
+                ]);
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    /// Write the mesh as an OBJ if `path` ends in `.obj`, as a binary glTF if it ends
+    /// in `.glb` and as a `.gltf` with its data in a `.bin` next to it otherwise.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("obj") => self.write_obj(path),
+            Some("glb") => self.write_glb(path),
+            Some("gltf") => self.write_gltf(path),
+            _ => Err(Error::Format(format!(
+                "can't tell what kind of mesh to write to {} - use .obj, .gltf or .glb",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Write the mesh to a Wavefront OBJ file.
+    ///
+    /// OBJ's UVs start at the bottom of the image, so `v` is flipped on the way out.
+    pub fn write_obj(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        writeln!(file, "# Eroded terrain, {} vertices", self.positions.len())?;
+        for [x, y, z] in &self.positions {
+            writeln!(file, "v {x} {y} {z}")?;
+        }
+        for [u, v] in &self.uvs {
+            writeln!(file, "vt {u} {}", 1.0 - v)?;
+        }
+        for [x, y, z] in &self.normals {
+            writeln!(file, "vn {x} {y} {z}")?;
+        }
+        // OBJ counts from 1, and every vertex has the same index for all three
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+            writeln!(file, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+        }
+
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Write the mesh to a `.gltf` file, with its vertex data in a `.bin` file of the
+    /// same name next to it.
+    pub fn write_gltf(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let bin = path.with_extension("bin");
+        let uri = bin
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let buffer = self.gltf_buffer();
+        std::fs::write(&bin, &buffer)?;
+        std::fs::write(path, self.gltf_json(buffer.len(), Some(&uri)).to_string())?;
+
+        Ok(())
+    }
+
+    /// Write the mesh to a binary `.glb` file, with its vertex data inside.
+    ///
+    /// A `.glb` can't be 4 GiB or more, so bigger meshes give an `Error::Format` - write
+    /// them as a `.gltf` instead.
+    pub fn write_glb(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut buffer = self.gltf_buffer();
+        let mut json = self.gltf_json(buffer.len(), None).to_string().into_bytes();
+
+        // Both chunks have to be padded to 4 bytes - the JSON with spaces
+        json.resize(json.len().next_multiple_of(4), b' ');
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+        // Every length in the file is a `u32`, and the whole file's is the biggest
+        let length = u32::try_from(12 + 8 + json.len() + 8 + buffer.len()).map_err(|_| {
+            Error::Format("the mesh is too big for a .glb - use .gltf instead".to_string())
+        })?;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"glTF")?;
+        file.write_all(&2u32.to_le_bytes())?;
+        file.write_all(&length.to_le_bytes())?;
+        file.write_all(&(json.len() as u32).to_le_bytes())?;
+        file.write_all(b"JSON")?;
+        file.write_all(&json)?;
+        file.write_all(&(buffer.len() as u32).to_le_bytes())?;
+        file.write_all(b"BIN\0")?;
+        file.write_all(&buffer)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// The positions, normals, UVs and indices one after the other, as glTF wants them.
+    fn gltf_buffer(&self) -> Vec<u8> {
+        let floats = self
+            .positions
+            .iter()
+            .chain(&self.normals)
+            .flatten()
+            .chain(self.uvs.iter().flatten());
+
+        floats
+            .flat_map(|value| value.to_le_bytes())
+            .chain(self.indices.iter().flat_map(|index| index.to_le_bytes()))
+            .collect()
+    }
+
+    /// The glTF description of the mesh, for a buffer of `length` bytes laid out by
+    /// `gltf_buffer` that's at `uri`, or inside a `.glb` without one.
+    fn gltf_json(&self, length: usize, uri: Option<&str>) -> serde_json::Value {
+        let vertices = self.positions.len();
+        let positions = vertices * 12;
+        let normals = vertices * 12;
+        let uvs = vertices * 8;
+        let indices = self.indices.len() * 4;
+
+        // POSITION accessors have to say how far the mesh reaches
+        let (min, max) = self.positions.iter().fold(
+            ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]),
+            |(mut min, mut max), position| {
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(position[axis]);
+                    max[axis] = max[axis].max(position[axis]);
+                }
+                (min, max)
+            },
+        );
+
+        let mut buffer = json!({ "byteLength": length });
+        if let Some(uri) = uri {
+            buffer["uri"] = json!(uri);
+        }
+
+        // Constants from the glTF spec
+        const FLOAT: u32 = 5126;
+        const UNSIGNED_INT: u32 = 5125;
+        const ARRAY_BUFFER: u32 = 34962;
+        const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+        const TRIANGLES: u32 = 4;
+
+        json!({
+            "asset": { "version": "2.0", "generator": "erosion-simulation" },
+            "scene": 0,
+            "scenes": [{ "nodes": [0] }],
+            "nodes": [{ "mesh": 0, "name": "terrain" }],
+            "meshes": [{
+                "name": "terrain",
+                "primitives": [{
+                    "attributes": { "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2 },
+                    "indices": 3,
+                    "mode": TRIANGLES,
+                }],
+            }],
+            "buffers": [buffer],
+            "bufferViews": [
+                { "buffer": 0, "byteOffset": 0, "byteLength": positions, "target": ARRAY_BUFFER },
+                { "buffer": 0, "byteOffset": positions, "byteLength": normals, "target": ARRAY_BUFFER },
+                { "buffer": 0, "byteOffset": positions + normals, "byteLength": uvs, "target": ARRAY_BUFFER },
+                {
+                    "buffer": 0,
+                    "byteOffset": positions + normals + uvs,
+                    "byteLength": indices,
+                    "target": ELEMENT_ARRAY_BUFFER,
+                },
+            ],
+            "accessors": [
+                {
+                    "bufferView": 0,
+                    "componentType": FLOAT,
+                    "count": vertices,
+                    "type": "VEC3",
+                    "min": min,
+                    "max": max,
+                },
+                { "bufferView": 1, "componentType": FLOAT, "count": vertices, "type": "VEC3" },
+                { "bufferView": 2, "componentType": FLOAT, "count": vertices, "type": "VEC2" },
+                {
+                    "bufferView": 3,
+                    "componentType": UNSIGNED_INT,
+                    "count": self.indices.len(),
+                    "type": "SCALAR",
+                },
+            ],
+        })
+    }
+}
+
+/// The cells along an edge `len` long that get a vertex, every `step` cells and always
+/// the last one.
+fn sample_points(len: usize, step: usize) -> Vec<usize> {
+    let mut points: Vec<usize> = (0..len).step_by(step.max(1)).collect();
+    if points.last() != Some(&(len - 1)) {
+        points.push(len - 1);
+    }
+
+    points
+}
+
+/// Scale a vector to a length of one.
+fn normalize([x, y, z]: [f32; 3]) -> [f32; 3] {
+    let length = (x * x + y * y + z * z).sqrt();
+
+    [x / length, y / length, z / length]
+}
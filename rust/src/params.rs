@@ -0,0 +1,461 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+use crate::io::EXPOSED_CHANNEL;
+
+/// The names of the built-in presets, in the form accepted by `ErosionParams::preset`.
+pub const PRESETS: &[&str] = &["gentle hills", "badlands", "alpine"];
+
+/// The erosion models the simulation can run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErosionModel {
+    /// Particle-based erosion using `Raindrop`s.
+    #[default]
+    Droplet,
+    /// Grid-based erosion using the virtual pipe shallow-water method.
+    ShallowWater,
+}
+
+impl ErosionModel {
+    /// The names of every model, in the form accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["droplet", "shallow_water"];
+}
+
+impl FromStr for ErosionModel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().replace([' ', '-'], "_").as_str() {
+            "droplet" => Ok(ErosionModel::Droplet),
+            "shallow_water" => Ok(ErosionModel::ShallowWater),
+            _ => Err(Error::Format(format!(
+                "unknown erosion model {s:?}, expected one of: {}",
+                ErosionModel::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ErosionModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErosionModel::Droplet => write!(f, "droplet"),
+            ErosionModel::ShallowWater => write!(f, "shallow_water"),
+        }
+    }
+}
+
+/// What happens to a `Raindrop` that runs off the edge of the map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Boundary {
+    /// The drop dies and drops its sediment at the edge.
+    #[default]
+    Deposit,
+    /// The drop dies and its sediment is lost, so no rim builds up along the edges.
+    Discard,
+    /// The drop bounces back off the edge.
+    Reflect,
+    /// The drop is held at the edge and slides along it.
+    Clamp,
+    /// The drop comes back in on the opposite edge, as if the map repeats - and so does
+    /// everything else, so the eroded map tiles seamlessly.
+    Wrap,
+}
+
+impl Boundary {
+    /// The names of every boundary, in the form accepted by `from_str`.
+    pub const NAMES: &'static [&'static str] = &["deposit", "discard", "reflect", "clamp", "wrap"];
+}
+
+impl FromStr for Boundary {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "deposit" => Ok(Boundary::Deposit),
+            "discard" => Ok(Boundary::Discard),
+            "reflect" => Ok(Boundary::Reflect),
+            "clamp" => Ok(Boundary::Clamp),
+            "wrap" => Ok(Boundary::Wrap),
+            _ => Err(Error::Format(format!(
+                "unknown boundary {s:?}, expected one of: {}",
+                Boundary::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Boundary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Boundary::Deposit => write!(f, "deposit"),
+            Boundary::Discard => write!(f, "discard"),
+            Boundary::Reflect => write!(f, "reflect"),
+            Boundary::Clamp => write!(f, "clamp"),
+            Boundary::Wrap => write!(f, "wrap"),
+        }
+    }
+}
+
+/// Every tunable parameter of the simulation.
+///
+/// Can be saved to and loaded from TOML or JSON files so tuned settings can be
+/// shared. Any field missing from a file keeps its default value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErosionParams {
+    /// Which erosion model each iteration runs.
+    pub model: ErosionModel,
+    /// How strongly slopes speed `Raindrop`s up.
+    #[serde(serialize_with = "shortest_f32")]
+    pub gravity: f32,
+    /// Carrying capcity of the `Raindrop` - how much sediment it can carry.
+    #[serde(serialize_with = "shortest_f32")]
+    pub capacity: f32,
+    /// How much of its previous direction a `Raindrop` keeps each step.
+    #[serde(serialize_with = "shortest_f32")]
+    pub inertia: f32,
+    /// How quickly `Raindrop`s pick up sediment.
+    #[serde(serialize_with = "shortest_f32")]
+    pub erosion_factor: f32,
+    /// How quickly `Raindrop`s drop excess sediment.
+    #[serde(serialize_with = "shortest_f32")]
+    pub deposition_factor: f32,
+    /// The diameter of the `Raindrop` - how much area it covers.
+    /// This should almost always be >= 3.0, otherwise we get weird
+    /// artifacts and terrible simulation.
+    #[serde(serialize_with = "shortest_f32")]
+    pub diameter: f32,
+    /// The maximum number of steps a `Raindrop` takes.
+    pub lifetime: u32,
+    /// How much water a `Raindrop` starts with.
+    #[serde(serialize_with = "shortest_f32")]
+    pub starting_mass: f32,
+    /// The fraction of a `Raindrop`'s water that evaporates each step.
+    #[serde(serialize_with = "shortest_f32")]
+    pub evaporation: f32,
+    /// `Raindrop`s slower than this die.
+    #[serde(serialize_with = "shortest_f32")]
+    pub min_velocity: f32,
+    /// What happens to `Raindrop`s that run off the edge of the map.
+    pub boundary: Boundary,
+    /// How many `Raindrop`s are simulated each iteration.
+    pub drop_count: usize,
+    /// Simulate `Raindrop`s tile by tile so each sees the changes made by the ones
+    /// before it, rather than all of them eroding the terrain as it was at the start
+    /// of the iteration.
+    pub tiled: bool,
+    /// How many coarser copies of the heightmap, each half the size of the one above, to
+    /// erode before the full-resolution one - `0` starts at full resolution.
+    pub pyramid_levels: u32,
+    /// How many iterations to run on each of the coarser levels.
+    pub pyramid_iterations: u32,
+    /// The steepest slope thermal weathering leaves alone, as the height difference per cell.
+    #[serde(serialize_with = "shortest_f32")]
+    pub thermal_talus: f32,
+    /// The fraction of the material over the talus moved by each thermal pass.
+    #[serde(serialize_with = "shortest_f32")]
+    pub thermal_rate: f32,
+    /// Run a thermal pass after every this many iterations - `0` turns them off.
+    pub thermal_interval: u32,
+    /// How quickly rock hardens as the material above it is eroded away, and softens
+    /// under fresh sediment - `0.0` keeps the hardness map as it was loaded.
+    #[serde(serialize_with = "shortest_f32")]
+    pub hardening: f32,
+    /// How much more rain the highest point gets than the lowest - `1.0` doubles it.
+    #[serde(serialize_with = "shortest_f32")]
+    pub rain_elevation: f32,
+    /// How much more rain the steepest slope facing into the wind gets - `1.0` doubles it.
+    #[serde(serialize_with = "shortest_f32")]
+    pub rain_windward: f32,
+    /// The direction the wind blows towards, in degrees clockwise from the `+x` axis.
+    #[serde(serialize_with = "shortest_f32")]
+    pub rain_wind_direction: f32,
+    /// The fraction of the flow map kept from one iteration to the next - the rest fades
+    /// away so it follows where the water runs now.
+    #[serde(serialize_with = "shortest_f32")]
+    pub flow_memory: f32,
+    /// The parameters of the shallow-water model.
+    pub shallow_water: ShallowWaterParams,
+    /// The material layers the terrain is made of, from the top down - empty for
+    /// terrain that's the same all the way through.
+    pub layers: Vec<LayerParams>,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            model: ErosionModel::Droplet,
+            gravity: 10.0,
+            capacity: 2.0,
+            inertia: 0.3,
+            erosion_factor: 0.3,
+            deposition_factor: 0.3,
+            diameter: 3.0,
+            lifetime: 50,
+            starting_mass: 1.0,
+            evaporation: 0.02,
+            min_velocity: 0.01,
+            boundary: Boundary::Deposit,
+            drop_count: 20_000,
+            tiled: false,
+            pyramid_levels: 0,
+            pyramid_iterations: 20,
+            thermal_talus: 0.01,
+            thermal_rate: 0.5,
+            thermal_interval: 0,
+            hardening: 0.0,
+            rain_elevation: 0.0,
+            rain_windward: 0.0,
+            rain_wind_direction: 0.0,
+            flow_memory: 0.9,
+            shallow_water: ShallowWaterParams::default(),
+            layers: Vec::new(),
+        }
+    }
+}
+
+/// The parameters of the grid-based shallow-water model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShallowWaterParams {
+    /// How many time steps make up a single iteration.
+    pub steps: u32,
+    /// The length of a single time step.
+    #[serde(serialize_with = "shortest_f32")]
+    pub time_step: f32,
+    /// How much water rains onto the average cell per unit of time.
+    #[serde(serialize_with = "shortest_f32")]
+    pub rain_rate: f32,
+    /// How strongly differences in water height push water between cells.
+    #[serde(serialize_with = "shortest_f32")]
+    pub gravity: f32,
+    /// How much sediment the water can carry for its speed and slope.
+    #[serde(serialize_with = "shortest_f32")]
+    pub sediment_capacity: f32,
+    /// How quickly the water dissolves terrain when it can carry more.
+    #[serde(serialize_with = "shortest_f32")]
+    pub dissolving: f32,
+    /// How quickly the water deposits sediment it can't carry.
+    #[serde(serialize_with = "shortest_f32")]
+    pub deposition: f32,
+    /// The fraction of the water that evaporates per unit of time.
+    #[serde(serialize_with = "shortest_f32")]
+    pub evaporation: f32,
+    /// The smallest tilt used for the carrying capacity, so flat ground still erodes.
+    #[serde(serialize_with = "shortest_f32")]
+    pub min_tilt: f32,
+    /// Water deeper than this carries no more sediment than water this deep.
+    #[serde(serialize_with = "shortest_f32")]
+    pub max_erosion_depth: f32,
+}
+
+impl Default for ShallowWaterParams {
+    fn default() -> Self {
+        ShallowWaterParams {
+            steps: 10,
+            time_step: 0.05,
+            rain_rate: 0.01,
+            gravity: 10.0,
+            sediment_capacity: 1.0,
+            dissolving: 0.5,
+            deposition: 0.5,
+            evaporation: 0.1,
+            min_tilt: 0.01,
+            max_erosion_depth: 0.05,
+        }
+    }
+}
+
+/// A single layer of material in the terrain, see `Strata`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayerParams {
+    /// The name of the layer, used for its channel when the layers are exported.
+    pub name: String,
+    /// How thick the layer starts out. Ignored for the bottom layer, which goes down forever.
+    #[serde(serialize_with = "shortest_f32")]
+    pub thickness: f32,
+    /// How easily the layer is eroded, from `0.0` (not at all) to `1.0` (as easily as possible).
+    #[serde(serialize_with = "shortest_f32")]
+    pub erodibility: f32,
+}
+
+impl Default for LayerParams {
+    fn default() -> Self {
+        LayerParams {
+            name: "layer".to_string(),
+            thickness: 0.0,
+            erodibility: 1.0,
+        }
+    }
+}
+
+impl ErosionParams {
+    /// Whether the map wraps around its edges, so every part of the simulation treats
+    /// it as tiling seamlessly.
+    pub fn wraps(&self) -> bool {
+        self.boundary == Boundary::Wrap
+    }
+
+    /// Check a heightmap with dimensions `dims` is big enough to erode. Drops start
+    /// between cells, so a map that doesn't wrap has to be at least 2x2 - one that
+    /// wraps just can't be empty.
+    pub fn check_dims(&self, dims: (usize, usize)) -> Result<()> {
+        let min = if self.wraps() { 1 } else { 2 };
+        if dims.0 < min || dims.1 < min {
+            return Err(Error::Format(format!(
+                "a {}x{} heightmap is too small to erode - it has to be at least {min}x{min}",
+                dims.0, dims.1
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Get one of the built-in presets by name (see `PRESETS`).
+    ///
+    /// Names are matched ignoring case, and `_`/`-` can be used in place of spaces.
+    pub fn preset(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase().replace(['_', '-'], " ");
+
+        match name.as_str() {
+            // Soft, rounded terrain - light erosion smeared over a wide area
+            "gentle hills" => Some(ErosionParams {
+                capacity: 1.5,
+                inertia: 0.2,
+                erosion_factor: 0.1,
+                deposition_factor: 0.5,
+                diameter: 5.0,
+                lifetime: 40,
+                ..Default::default()
+            }),
+            // Dense networks of sharp gullies - aggressive, narrow erosion
+            "badlands" => Some(ErosionParams {
+                capacity: 4.0,
+                inertia: 0.05,
+                erosion_factor: 0.6,
+                deposition_factor: 0.1,
+                diameter: 3.0,
+                lifetime: 60,
+                evaporation: 0.01,
+                ..Default::default()
+            }),
+            // Long, deep valleys carved by fast water that travels a long way
+            "alpine" => Some(ErosionParams {
+                gravity: 20.0,
+                capacity: 6.0,
+                inertia: 0.4,
+                erosion_factor: 0.4,
+                deposition_factor: 0.2,
+                diameter: 4.0,
+                lifetime: 80,
+                evaporation: 0.015,
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Load parameters from a `.toml` or `.json` file.
+    ///
+    /// Fails if the layers can't be told apart, see `validate`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+
+        let params: ErosionParams = match ParamsFormat::from_path(path)? {
+            ParamsFormat::Toml => {
+                toml::from_str(&contents).map_err(|e| Error::Format(e.to_string()))?
+            }
+            ParamsFormat::Json => {
+                serde_json::from_str(&contents).map_err(|e| Error::Format(e.to_string()))?
+            }
+        };
+        params.validate()?;
+
+        Ok(params)
+    }
+
+    /// Check every layer has a name of its own, since they're exported as channels named
+    /// after them - so names can't be empty, used twice, or the reserved `exposed`.
+    pub fn validate(&self) -> Result<()> {
+        for (index, layer) in self.layers.iter().enumerate() {
+            if layer.name.is_empty() {
+                return Err(Error::Format(format!("layer {index} has no name")));
+            }
+            if layer.name == EXPOSED_CHANNEL {
+                return Err(Error::Format(format!(
+                    "layer {index} can't be called {EXPOSED_CHANNEL:?}, that channel is reserved"
+                )));
+            }
+            if self.layers[..index]
+                .iter()
+                .any(|other| other.name == layer.name)
+            {
+                return Err(Error::Format(format!(
+                    "more than one layer is called {:?} - give every layer its own name",
+                    layer.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save the parameters to a `.toml` or `.json` file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        let contents = match ParamsFormat::from_path(path)? {
+            ParamsFormat::Toml => {
+                toml::to_string_pretty(self).map_err(|e| Error::Format(e.to_string()))?
+            }
+            ParamsFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|e| Error::Format(e.to_string()))?
+            }
+        };
+
+        std::fs::write(path, contents)?;
+
+        Ok(())
+    }
+}
+
+/// Serializes an `f32` using its shortest decimal representation.
+///
+/// TOML widens `f32`s to `f64`s, which would otherwise write `0.05` as `0.05000000074505806`.
+fn shortest_f32<S: Serializer>(value: &f32, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap())
+}
+
+/// The file formats parameters can be stored in.
+enum ParamsFormat {
+    Toml,
+    Json,
+}
+
+impl ParamsFormat {
+    /// Pick the format from the file extension.
+    fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+
+        match extension.as_deref() {
+            Some("toml") => Ok(ParamsFormat::Toml),
+            Some("json") => Ok(ParamsFormat::Json),
+            _ => Err(Error::Format(format!(
+                "{} isn't a .toml or .json file",
+                path.display()
+            ))),
+        }
+    }
+}
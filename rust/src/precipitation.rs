@@ -0,0 +1,172 @@
+use nalgebra::Vector2;
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::surface::neighbours;
+
+/// Picks cells at random in proportion to how much rain they get, in constant time
+/// per sample.
+///
+/// # Explanation
+///
+/// This is Vose's alias method. Every cell gets a bucket holding the chance of
+/// keeping its own index and an alias to hand out otherwise. The buckets are
+/// filled by pairing cells that get less than the average rain with cells that
+/// get more, so sampling is just one random bucket and one random number - no
+/// matter how big the map is. Building the table is linear in the number of
+/// cells, and it takes 8 bytes per cell.
+#[derive(Debug, Clone)]
+pub struct RainSampler {
+    // The chance of keeping each bucket's own cell instead of its alias
+    keep: Vec<f32>,
+    // The cell each bucket hands out when it doesn't keep its own
+    alias: Vec<u32>,
+}
+
+impl RainSampler {
+    /// Build a sampler from a weight for every cell.
+    ///
+    /// Negative and non-finite weights count as `0.0`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if no cell has any weight, as there'd be nowhere for rain to fall.
+    pub fn new(weights: &[f32]) -> Option<Self> {
+        let weights: Vec<f64> = weights
+            .par_iter()
+            .map(|&weight| {
+                if weight.is_finite() {
+                    weight.max(0.0) as f64
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        // Scale the weights so the average cell has exactly 1.0
+        let cells = weights.len();
+        let mut scaled: Vec<f64> = weights
+            .into_iter()
+            .map(|weight| weight * cells as f64 / total)
+            .collect();
+
+        let (mut small, mut large): (Vec<u32>, Vec<u32>) =
+            (0..cells as u32).partition(|&cell| scaled[cell as usize] < 1.0);
+
+        let mut keep = vec![1.0; cells];
+        let mut alias: Vec<u32> = (0..cells as u32).collect();
+
+        // Top up every under-full bucket with some of an over-full one
+        while let (Some(&under), Some(&over)) = (small.last(), large.last()) {
+            small.pop();
+            keep[under as usize] = scaled[under as usize] as f32;
+            alias[under as usize] = over;
+
+            scaled[over as usize] -= 1.0 - scaled[under as usize];
+            if scaled[over as usize] < 1.0 {
+                large.pop();
+                small.push(over);
+            }
+        }
+
+        // Whatever's left keeps its own cell, give or take floating point error
+        Some(RainSampler { keep, alias })
+    }
+
+    /// The number of cells the sampler picks from.
+    pub fn len(&self) -> usize {
+        self.keep.len()
+    }
+
+    /// Whether the sampler has no cells - never true, as `new` needs some weight.
+    pub fn is_empty(&self) -> bool {
+        self.keep.is_empty()
+    }
+
+    /// Pick the index of a cell, drawing from `rng`.
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        let bucket = rng.gen_range(0..self.keep.len());
+
+        if rng.gen::<f32>() < self.keep[bucket] {
+            bucket
+        } else {
+            self.alias[bucket] as usize
+        }
+    }
+}
+
+/// Work out how much rain every cell gets from the shape of the terrain.
+///
+/// # Arguments
+///
+/// * `heightmap` - The terrain, stored row by row.
+/// * `dims` - The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`.
+/// * `elevation` - How much more rain the highest point gets than the lowest - `1.0` doubles it.
+/// * `windward` - How much more rain the steepest slope facing into the wind gets - `1.0`
+///   doubles it.
+/// * `wind_direction` - The direction the wind blows towards, in degrees clockwise from
+///   the `+x` axis.
+/// * `wrap` - Whether the heightmap repeats, so slopes are worked out across its edges.
+///
+/// # Returns
+///
+/// A weight for every cell, where flat ground at the lowest point gets `1.0`.
+///
+/// # Explanation
+///
+/// Air cools as it rises, so it drops more of its water higher up - and most of
+/// all where the wind pushes it up a slope. Slopes in the lee of the wind get no
+/// more than flat ground, so rain shadows form behind ridges.
+pub fn orographic_rain(
+    heightmap: &[f32],
+    dims: (usize, usize),
+    elevation: f32,
+    windward: f32,
+    wind_direction: f32,
+    wrap: bool,
+) -> Vec<f32> {
+    let (low, high) = heightmap
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(low, high), &height| {
+            (low.min(height), high.max(height))
+        });
+    let range = (high - low).max(f32::EPSILON);
+
+    let wind = Vector2::new(
+        wind_direction.to_radians().cos(),
+        wind_direction.to_radians().sin(),
+    );
+
+    // How steeply the ground rises going downwind
+    let uphill: Vec<f32> = (0..heightmap.len())
+        .into_par_iter()
+        .map(|index| {
+            let x = index % dims.0;
+            let y = index / dims.0;
+            let height = |x: usize, y: usize| heightmap[y * dims.0 + x];
+
+            let (left, right, up, down) = neighbours(x, y, dims, wrap);
+
+            let gradient = Vector2::new(
+                (height(right, y) - height(left, y)) / 2.0,
+                (height(x, down) - height(x, up)) / 2.0,
+            );
+
+            gradient.dot(&wind).max(0.0)
+        })
+        .collect();
+    let steepest = uphill.iter().copied().fold(f32::EPSILON, f32::max);
+
+    heightmap
+        .par_iter()
+        .zip(&uphill)
+        .map(|(height, uphill)| {
+            1.0 + elevation * (height - low) / range + windward * uphill / steepest
+        })
+        .collect()
+}
@@ -0,0 +1,94 @@
+use rayon::prelude::*;
+
+use crate::params::ErosionParams;
+
+/// Halve a map's resolution by averaging every 2x2 block of cells into one.
+///
+/// Maps with an odd width or height keep their last column or row, averaged
+/// over just the cells there are.
+///
+/// # Returns
+///
+/// The downsampled map and its dimensions as a tuple of `(x: usize, y: usize)`.
+pub fn downsample(map: &[f32], dims: (usize, usize)) -> (Vec<f32>, (usize, usize)) {
+    let coarse_dims = (dims.0.div_ceil(2), dims.1.div_ceil(2));
+
+    let coarse = (0..coarse_dims.0 * coarse_dims.1)
+        .into_par_iter()
+        .map(|index| {
+            let (x, y) = ((index % coarse_dims.0) * 2, (index / coarse_dims.0) * 2);
+
+            let mut sum = 0.0;
+            let mut count = 0;
+            for (x, y) in [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+                if x < dims.0 && y < dims.1 {
+                    sum += map[y * dims.0 + x];
+                    count += 1;
+                }
+            }
+
+            sum / count as f32
+        })
+        .collect();
+
+    (coarse, coarse_dims)
+}
+
+/// Bring a map downsampled with `downsample` back up to dimensions `dims`, blending
+/// between coarse cells bilinearly.
+pub fn upsample(coarse: &[f32], coarse_dims: (usize, usize), dims: (usize, usize)) -> Vec<f32> {
+    (0..dims.0 * dims.1)
+        .into_par_iter()
+        .map(|index| {
+            // Every coarse cell sits at the middle of the 2x2 block it was made from
+            let x = ((index % dims.0) as f32 - 0.5) / 2.0;
+            let y = ((index / dims.0) as f32 - 0.5) / 2.0;
+            let x = x.clamp(0.0, (coarse_dims.0 - 1) as f32);
+            let y = y.clamp(0.0, (coarse_dims.1 - 1) as f32);
+
+            let x0 = x.floor() as usize;
+            let y0 = y.floor() as usize;
+            let x1 = (x0 + 1).min(coarse_dims.0 - 1);
+            let y1 = (y0 + 1).min(coarse_dims.1 - 1);
+            let u = x.fract();
+            let v = y.fract();
+
+            let nw = coarse[y0 * coarse_dims.0 + x0];
+            let ne = coarse[y0 * coarse_dims.0 + x1];
+            let sw = coarse[y1 * coarse_dims.0 + x0];
+            let se = coarse[y1 * coarse_dims.0 + x1];
+
+            (nw * (1.0 - u) * (1.0 - v))
+                + (ne * u * (1.0 - v))
+                + (sw * (1.0 - u) * v)
+                + (se * u * v)
+        })
+        .collect()
+}
+
+/// The parameters to erode pyramid level `level` with - `0` is full resolution, and
+/// every level above it has half the resolution of the one below.
+///
+/// # Explanation
+///
+/// A coarse cell covers `2^level` full-resolution cells each way, so everything
+/// measured in cells shrinks to match: the brush `diameter` and `lifetime` (so
+/// drops cover the same ground), and the `drop_count` by the number of cells (so
+/// the same amount of rain falls per area). The `thermal_talus` is a height
+/// difference per cell, so it grows instead.
+///
+/// The material layers are left out, as coarse levels are given the resistance they
+/// add up to as their hardness map instead - see `ErosionSimulator::run_pyramid`.
+pub fn level_params(params: &ErosionParams, level: u32) -> ErosionParams {
+    let scale = (1u64 << level.min(32)) as f32;
+
+    ErosionParams {
+        diameter: (params.diameter / scale).max(1.0),
+        lifetime: ((params.lifetime as f32 / scale).round() as u32).max(1),
+        drop_count: (params.drop_count as f32 / (scale * scale)).round() as usize,
+        thermal_talus: params.thermal_talus * scale,
+        pyramid_levels: 0,
+        layers: Vec::new(),
+        ..params.clone()
+    }
+}
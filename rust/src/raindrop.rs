@@ -1,7 +1,9 @@
-use std::sync::{Arc, RwLock};
-
 use nalgebra::Vector2;
 
+use crate::brush::Brush;
+use crate::changes::ChangeBuffer;
+use crate::params::{Boundary, ErosionParams};
+
 #[derive(Debug)]
 pub struct Raindrop {
     // The mass contained by the drop
@@ -37,40 +39,49 @@ impl Raindrop {
     ///
     /// * `texture` - The texture to simulate on as a `&[f32]`.
     /// * `dims` - The dimensions of the texture as a tuple of `(x: usize, y: usize)`.
-    ///
-    /// # Returns
-    ///
-    /// A vector of tuples containing the amount of material deposited/eroded (based on sign) and the x/y coordinates.
-    /// Contained a the tuple `(material: f32, x: usize, y: usize)`.
+    /// * `params` - The `ErosionParams` controlling how the `Raindrop` behaves.
+    /// * `brush` - The `Brush` to erode and deposit with, built for `params.diameter`.
+    /// * `hardness` - An optional rock hardness for every cell of the texture, from `0.0` (soft)
+    ///   to `1.0` (can't be eroded).
+    /// * `changes` - Where the amount of material deposited/eroded (based on sign) in each cell
+    ///   is added up.
+    /// * `flow` - Where the water the `Raindrop` carries through each cell it visits is added up.
+    #[allow(clippy::too_many_arguments)]
     pub fn simulate(
         &mut self,
-        texture: Arc<RwLock<Vec<f32>>>,
+        texture: &[f32],
         dims: (usize, usize),
-        gravity: f32,
-        capacity: f32,
-        inertia: f32,
-        erosion_factor: f32,
-        deposition_factor: f32,
-        diameter: f32,
-        lifetime: u32,
-    ) -> Vec<(f32, usize)> {
-        // Create a vector to store changes
-        let mut changes = Vec::with_capacity(
-            ((diameter / 2.0).powi(2) * std::f32::consts::PI).ceil() as usize
-                + (diameter * lifetime as f32).ceil() as usize,
-        );
-
-        // Grab a read lock on the texture
-        let texture = texture.read().unwrap();
+        params: &ErosionParams,
+        brush: &Brush,
+        hardness: Option<&[f32]>,
+        changes: &mut ChangeBuffer,
+        flow: &mut ChangeBuffer,
+    ) {
+        let ErosionParams {
+            gravity,
+            capacity,
+            inertia,
+            erosion_factor,
+            deposition_factor,
+            lifetime,
+            evaporation,
+            min_velocity,
+            boundary,
+            ..
+        } = *params;
+        let wrap = params.wraps();
 
         for _ in 0..lifetime {
             // Store current position for later
             let prev_x = self.position.x;
             let prev_y = self.position.y;
 
+            // Record the water passing through this cell
+            flow.add(prev_y as usize * dims.0 + prev_x as usize, self.water);
+
             // Find slope of the terrain at the Raindrop's position
             let (starting_height, gradient) =
-                get_height_and_gradient(self.position, &texture, dims);
+                get_height_and_gradient(self.position, texture, dims, wrap);
 
             // Find the new direction of the Raindrop - normalize so we only step exactly 1 unit
             self.direction =
@@ -79,19 +90,31 @@ impl Raindrop {
             // Set the droplet to the new position
             self.position += self.direction;
 
-            // If the Raindrop is out of bounds, reflect it
-            if self.position.x >= (dims.0 - 1) as f32
-                || self.position.x < 0.0
-                || self.position.y >= (dims.1 - 1) as f32
-                || self.position.y < 0.0
-                || self.velocity <= 0.01
-            {
-                self.kill(dims, diameter, &mut changes);
+            // If the Raindrop is out of bounds, handle it as the boundary says
+            if self.out_of_bounds(dims, wrap) {
+                match boundary {
+                    Boundary::Deposit => {
+                        self.kill(brush, changes, hardness);
+                        break;
+                    }
+                    Boundary::Discard => {
+                        self.alive = false;
+                        break;
+                    }
+                    Boundary::Reflect => self.reflect(dims),
+                    Boundary::Clamp => self.clamp(dims),
+                    Boundary::Wrap => self.wrap(dims),
+                }
+            }
+
+            // Kill Raindrops that have slowed down too much
+            if self.velocity <= min_velocity {
+                self.kill(brush, changes, hardness);
                 break;
             }
 
             // Get the height of the new position
-            let (height, _) = get_height_and_gradient(self.position, &texture, dims);
+            let (height, _) = get_height_and_gradient(self.position, texture, dims, wrap);
 
             // Get the height difference
             let diff = height - starting_height;
@@ -107,174 +130,191 @@ impl Raindrop {
                 };
 
                 self.erode_deposit(
-                    dims,
                     Vector2::new(prev_x, prev_y),
-                    diameter,
+                    brush,
                     deposit,
-                    &mut changes,
+                    changes,
+                    hardness,
                 );
             } else {
                 // Erode the sediment
                 // Use a negative value to indicate erosion
                 let deposit = -((sediment_capacity - self.sediment) * erosion_factor).min(-diff);
                 self.erode_deposit(
-                    dims,
                     Vector2::new(prev_x, prev_y),
-                    diameter,
+                    brush,
                     deposit,
-                    &mut changes,
+                    changes,
+                    hardness,
                 );
             }
 
             // Calculate the new velocity
             self.velocity = (self.velocity.powi(2) + diff * gravity).sqrt().max(0.0001);
 
-            // Evaporate 2% of the water
-            self.water *= 0.98;
+            // Evaporate some of the water
+            self.water *= 1.0 - evaporation;
         }
-
-        // godot_print!(
-        //     "Returning {} changes, {} alloc'd",
-        //     changes.len(),
-        //     changes.capacity()
-        // );
-
-        changes
     }
 
-    /// Modifies the given texture by a quadratic function for depositing/removing material.
+    /// Modifies the given texture by spreading material over the cells under the brush.
     ///
     /// # Arguments
     ///
-    /// * `dims` - The dimensions of the texture as a tuple of `(usize, usize)`.
-    /// * `diameter` - The diameter the `Raindrop` covers.
+    /// * `position` - Where the material is deposited/eroded.
+    /// * `brush` - The `Brush` to spread the material with.
     /// * `deposit` - The amount of material to deposit - can be negative to erode.
-    ///
-    /// # Returns
-    ///
-    /// A vector of tuples containing the amount of material deposited/eroded (based on sign) and the x/y coordinates.
-    /// Contained a the tuple `(material: f32, x: usize, y: usize)`.
+    /// * `changes` - Where the changes are added up.
+    /// * `hardness` - An optional rock hardness for every cell, which resists erosion.
     ///
     /// # Explanation
     ///
-    /// We know what square we're in with `self.position` - so we create a square// Deposit the material - check bounds
-    /// with diameter `diameter` around the `Raindrop`'s position, and then iterate
-    /// over them using a quadratic function to determine how much material to deposit.
-    ///
-    /// Because we have a diameter, we take the distance from `self.position` to all
-    /// points within range to establish a weight. Negative weights are ignored, and
-    /// positive weights are calculated using `(1 - weight)^2 * deposit`. These weights
-    /// are summed together and the sum is used to divide out the deposit among the
-    /// points.
+    /// The brush is centred on the cell containing `position`, and already knows how
+    /// much of the material each cell under it gets - using a quadratic falloff from
+    /// the centre, with the weights adding up to `1.0`. When eroding, each point's
+    /// share is then scaled by `1 - hardness`.
     pub fn erode_deposit(
         &mut self,
-        dims: (usize, usize),
         position: Vector2<f32>,
-        diameter: f32,
+        brush: &Brush,
         deposit: f32,
-        changes: &mut Vec<(f32, usize)>,
+        changes: &mut ChangeBuffer,
+        hardness: Option<&[f32]>,
     ) {
-        // Get the height of the square - rounding up so we don't miss points
-        let height = diameter.ceil() as usize;
-
-        // Since we're using a square, we'll need at most `(diameter + 1)^2` points.
-        //  Each point is a tuple of `(weight, x, y)`.
-        // There's some weird alloc capacity - this is because a sphere fills a circle
-        //  at a ratio of 4:pi, so we can use an approximation
-        let mut points: Vec<(f32, usize)> = Vec::with_capacity(
-            ((diameter / 2.0).powi(2) * std::f32::consts::PI).ceil() as usize + 1,
-        );
-
-        // Sum the weights of the points
-        let mut weight_sum = 0.0;
-
-        // Iterate over the points in the square - checking they're in bounds
-        for y in 0..=height {
-            // Skip columns that are out of bounds
-            let y_offset = diameter / 2.0 - y as f32;
-            if position.y - y_offset < 0.0 || position.y - y_offset >= dims.1 as f32 {
-                continue;
-            }
-            for x in 0..=height {
-                // Skip rows that are out of bounds
-                let x_offset = diameter / 2.0 - x as f32;
-                if position.x - x_offset < 0.0 || position.x - x_offset >= dims.0 as f32 {
-                    continue;
-                }
+        brush.for_each(position, |index, weight| {
+            // Calculate the deposit
+            let mut weighted_deposit = deposit * weight;
 
-                // Get the point - we have to floor it for integer indexing
-                let x = (position.x - x_offset).floor();
-                let y = (position.y - y_offset).floor();
+            // Harder rock gives up less material
+            if let Some(hardness) = hardness.filter(|_| deposit < 0.0) {
+                weighted_deposit *= 1.0 - hardness[index];
+            }
 
-                // Get the distance from the center
-                let distance = ((x - position.x).powi(2) + (y - position.y).powi(2)).sqrt();
+            // Add the change to any others made to the same cell
+            changes.add(index, weighted_deposit);
 
-                // Skip points that are out of the circle
-                if distance > diameter / 2.0 {
-                    continue;
-                }
+            // Remove sediment from the Raindrop
+            self.sediment -= weighted_deposit;
+        });
+    }
 
-                // We now have a point within the circle - calculate the weight
-                let weight = (1.0 - (distance / (diameter / 2.0))).powi(2);
+    /// Whether the `Raindrop` is somewhere its height can't be sampled - off the map, or
+    /// on the last row or column unless the map wraps around.
+    fn out_of_bounds(&self, dims: (usize, usize), wrap: bool) -> bool {
+        let (width, height) = max_position(dims, wrap);
 
-                // Push the point and weight to the vector
-                points.push((weight, y as usize * dims.0 + x as usize));
+        self.position.x >= width
+            || self.position.x < 0.0
+            || self.position.y >= height
+            || self.position.y < 0.0
+    }
 
-                // Add the weight to the sum
-                weight_sum += weight;
+    /// Bounces the `Raindrop` back off whichever edges it went past.
+    fn reflect(&mut self, dims: (usize, usize)) {
+        let (width, height) = max_position(dims, false);
+
+        for (axis, max) in [(0, width), (1, height)] {
+            if self.position[axis] < 0.0 {
+                self.position[axis] = -self.position[axis];
+                self.direction[axis] = -self.direction[axis];
+            } else if self.position[axis] >= max {
+                self.position[axis] = 2.0 * max - self.position[axis];
+                self.direction[axis] = -self.direction[axis];
             }
         }
 
-        // Iterate over the points and deposit material
-        // We can use an iter mut to reuse the points vector, saving
-        // an entire set of allocations
-        for (weight, index) in points.iter() {
-            // Calculate the deposit
-            let weighted_deposit = deposit * *weight / weight_sum;
+        self.clamp_position(dims);
+    }
 
-            // Push the change to the changes vector - checking for values with the same index
-            if let Some((deposit, _)) = changes.iter_mut().find(|(_, i)| *i == *index) {
-                // If we find a change, add the deposit to it
-                *deposit += weighted_deposit;
-            } else {
-                // Otherwise, push a new change
-                changes.push((weighted_deposit, *index));
+    /// Holds the `Raindrop` at whichever edges it went past, so it slides along them.
+    ///
+    /// The edge soaks up the part of its speed going into it, so a `Raindrop` pushed
+    /// straight into a wall or corner slows down and dies rather than piling up sediment
+    /// in one spot.
+    fn clamp(&mut self, dims: (usize, usize)) {
+        let (width, height) = max_position(dims, false);
+
+        for (axis, max) in [(0, width), (1, height)] {
+            if self.position[axis] < 0.0 || self.position[axis] >= max {
+                self.direction[axis] = 0.0;
             }
+        }
+        self.velocity *= self.direction.norm();
 
-            // Remove sediment from the Raindrop
-            self.sediment -= weighted_deposit;
+        self.clamp_position(dims);
+    }
+
+    /// Brings the `Raindrop` back in on the opposite side of whichever edges it went past.
+    fn wrap(&mut self, dims: (usize, usize)) {
+        self.position.x = self.position.x.rem_euclid(dims.0 as f32);
+        self.position.y = self.position.y.rem_euclid(dims.1 as f32);
+
+        // Rounding can leave a tiny negative position right on the edge
+        if self.position.x >= dims.0 as f32 {
+            self.position.x = 0.0;
+        }
+        if self.position.y >= dims.1 as f32 {
+            self.position.y = 0.0;
         }
     }
 
+    /// Moves the `Raindrop` to the nearest point its height can be sampled at.
+    fn clamp_position(&mut self, dims: (usize, usize)) {
+        let (width, height) = max_position(dims, false);
+
+        self.position.x = self.position.x.clamp(0.0, width.next_down());
+        self.position.y = self.position.y.clamp(0.0, height.next_down());
+    }
+
     /// Kills the `Raindrop`.
     ///
     /// This is a separate function because there may need to be additional logic.
-    pub fn kill(&mut self, dims: (usize, usize), diameter: f32, changes: &mut Vec<(f32, usize)>) {
+    pub fn kill(&mut self, brush: &Brush, changes: &mut ChangeBuffer, hardness: Option<&[f32]>) {
         self.alive = false;
-        self.erode_deposit(dims, self.position, diameter, self.sediment, changes);
+        self.erode_deposit(self.position, brush, self.sediment, changes, hardness);
+    }
+}
+
+/// The limits a `Raindrop`'s position has to stay below for its height to be sampled -
+/// the whole map if it wraps around, otherwise everything but the last row and column.
+fn max_position(dims: (usize, usize), wrap: bool) -> (f32, f32) {
+    if wrap {
+        (dims.0 as f32, dims.1 as f32)
+    } else {
+        ((dims.0 - 1) as f32, (dims.1 - 1) as f32)
     }
 }
 
 /// Get the height and gradient of a point in the texture.
 ///
+/// If `wrap` is set, the texture repeats - so points on the last row or column
+/// are blended with the first.
+///
 /// Returns a tuple containing the height and the 2D gradient vector.
 fn get_height_and_gradient(
     point: Vector2<f32>,
     texture: &[f32],
     dims: (usize, usize),
+    wrap: bool,
 ) -> (f32, Vector2<f32>) {
-    // Get the index of the grid point - this just makes it cleaner :)
-    let texture_index = point.y as usize * dims.0 + point.x as usize;
+    // Get the indices of the grid point and the ones to its right and below
+    let x = point.x as usize;
+    let y = point.y as usize;
+    let (right, below) = if wrap {
+        ((x + 1) % dims.0, (y + 1) % dims.1)
+    } else {
+        (x + 1, y + 1)
+    };
 
     // Get the u/v offset values from the top right of the grid point
     let u = point.x.fract();
     let v = point.y.fract();
 
     // Get the heights of the four corners of the grid point
-    let nw = texture[texture_index];
-    let ne = texture[texture_index + 1];
-    let sw = texture[texture_index + dims.0];
-    let se = texture[texture_index + dims.0 + 1];
+    let nw = texture[y * dims.0 + x];
+    let ne = texture[y * dims.0 + right];
+    let sw = texture[below * dims.0 + x];
+    let se = texture[below * dims.0 + right];
 
     // Calculate the gradient
     let gradient: Vector2<f32> = Vector2::new(
@@ -0,0 +1,402 @@
+use nalgebra::Vector2;
+use rayon::prelude::*;
+
+use crate::params::ShallowWaterParams;
+
+// Indices of the four outflow pipes of a cell
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+const TOP: usize = 2;
+const BOTTOM: usize = 3;
+
+/// A grid-based shallow-water erosion model using the virtual pipe method.
+///
+/// Where a `Raindrop` only exists until it evaporates, this keeps water height,
+/// outflow flux, velocity and suspended sediment for every cell between steps -
+/// so it can represent standing water, lakes and continuous river flow. See
+/// "Fast Hydraulic Erosion Simulation and Visualization on GPU" by Mei et al.
+///
+/// The terrain itself isn't stored here - it's passed into `step` so the same
+/// heightmap can be shared with the droplet model.
+#[derive(Debug, Clone)]
+pub struct ShallowWater {
+    /// The depth of the water in every cell.
+    pub water: Vec<f32>,
+    /// The amount of sediment suspended in the water of every cell.
+    pub sediment: Vec<f32>,
+    // The outflow through each cell's left, right, top and bottom pipes
+    flux: Vec<[f32; 4]>,
+    // The velocity of the water in every cell
+    velocity: Vec<Vector2<f32>>,
+    // The dimensions of the grid as a tuple of `(x: usize, y: usize)`
+    dims: (usize, usize),
+    // Whether the grid wraps around its edges instead of being walled in
+    wrap: bool,
+}
+
+impl ShallowWater {
+    /// Create a new, dry `ShallowWater` grid with the given dimensions.
+    pub fn new(dims: (usize, usize)) -> Self {
+        let cells = dims.0 * dims.1;
+
+        ShallowWater {
+            water: vec![0.0; cells],
+            sediment: vec![0.0; cells],
+            flux: vec![[0.0; 4]; cells],
+            velocity: vec![Vector2::zeros(); cells],
+            dims,
+            wrap: false,
+        }
+    }
+
+    /// Rebuild a grid from state saved with `water`, `sediment`, `flux` and `velocity`,
+    /// like a checkpoint.
+    ///
+    /// # Returns
+    ///
+    /// `None` if any of them don't have exactly one value for every cell.
+    pub fn from_state(
+        dims: (usize, usize),
+        water: Vec<f32>,
+        sediment: Vec<f32>,
+        flux: Vec<[f32; 4]>,
+        velocity: Vec<Vector2<f32>>,
+    ) -> Option<Self> {
+        let cells = dims.0 * dims.1;
+        if [water.len(), sediment.len(), flux.len(), velocity.len()] != [cells; 4] {
+            return None;
+        }
+
+        Some(ShallowWater {
+            water,
+            sediment,
+            flux,
+            velocity,
+            dims,
+            wrap: false,
+        })
+    }
+
+    /// The dimensions of the grid as a tuple of `(x: usize, y: usize)`.
+    pub fn dims(&self) -> (usize, usize) {
+        self.dims
+    }
+
+    /// Whether the grid wraps around its edges.
+    pub fn wraps(&self) -> bool {
+        self.wrap
+    }
+
+    /// Set whether the grid wraps around its edges, so water flows out of one side and
+    /// into the other rather than the edges being walls.
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.wrap = wrap;
+    }
+
+    /// The outflow through every cell's left, right, top and bottom pipes.
+    pub fn flux(&self) -> &[[f32; 4]] {
+        &self.flux
+    }
+
+    /// The velocity of the water in every cell.
+    pub fn velocity(&self) -> &[Vector2<f32>] {
+        &self.velocity
+    }
+
+    /// Advance the simulation by a single time step, eroding and depositing on `terrain`.
+    ///
+    /// If a `hardness` is given for every cell, from `0.0` (soft) to `1.0` (can't be eroded),
+    /// it slows down how quickly the water dissolves each cell. If a `rain` weight is given
+    /// for every cell, each gets `params.rain_rate` times its weight instead of all the same.
+    ///
+    /// # Returns
+    ///
+    /// The number of cells of `terrain` that were changed.
+    ///
+    /// # Explanation
+    ///
+    /// Each step rains onto every cell, updates the flux through the virtual pipes
+    /// between neighbouring cells from the difference in water surface height,
+    /// moves the water and derives its velocity from the flux. The water then
+    /// dissolves or deposits sediment depending on how much it can carry at that
+    /// velocity and slope, the sediment is carried along with the flow, and some
+    /// of the water evaporates.
+    ///
+    /// The edges of the map are walls, so water only leaves by evaporating and
+    /// collects into lakes in closed basins - unless the grid wraps, in which case
+    /// water leaving one edge comes in at the other.
+    ///
+    /// Every stage reads from the state of the previous one, so cells can be updated
+    /// in parallel without the result depending on the order.
+    pub fn step(
+        &mut self,
+        terrain: &mut [f32],
+        params: &ShallowWaterParams,
+        hardness: Option<&[f32]>,
+        rain: Option<&[f32]>,
+    ) -> usize {
+        let dt = params.time_step;
+
+        // Rain on every cell
+        self.water
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(index, water)| {
+                let weight = rain.map_or(1.0, |rain| rain[index]);
+                *water += params.rain_rate * weight * dt;
+            });
+
+        self.update_flux(terrain, params);
+        self.update_water_and_velocity(dt);
+        let changed = self.erode_deposit(terrain, params, hardness);
+        self.advect_sediment(dt);
+
+        // Evaporate some of the water
+        let evaporation = (1.0 - params.evaporation * dt).max(0.0);
+        self.water
+            .par_iter_mut()
+            .for_each(|water| *water *= evaporation);
+
+        changed
+    }
+
+    /// Update the outflow through every pipe from the difference in water surface height.
+    fn update_flux(&mut self, terrain: &[f32], params: &ShallowWaterParams) {
+        let dt = params.time_step;
+        let dims = self.dims;
+        let wrap = self.wrap;
+        let water = &self.water;
+
+        self.flux
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(index, flux)| {
+                let surface = terrain[index] + water[index];
+
+                for (pipe, neighbour) in neighbours(index, dims, wrap).into_iter().enumerate() {
+                    // The edges of the map are walls - nothing flows through them
+                    flux[pipe] = match neighbour {
+                        Some(neighbour) => {
+                            let difference = surface - terrain[neighbour] - water[neighbour];
+                            (flux[pipe] + dt * params.gravity * difference).max(0.0)
+                        }
+                        None => 0.0,
+                    };
+                }
+
+                // Scale the outflow down so a cell can't lose more water than it has
+                let outflow: f32 = flux.iter().sum();
+                if outflow > 0.0 {
+                    let scale = (water[index] / (outflow * dt)).min(1.0);
+                    flux.iter_mut().for_each(|pipe| *pipe *= scale);
+                }
+            });
+    }
+
+    /// Move the water along the flux and work out its velocity.
+    fn update_water_and_velocity(&mut self, dt: f32) {
+        let dims = self.dims;
+        let wrap = self.wrap;
+        let flux = &self.flux;
+        let water = &self.water;
+
+        let updated: Vec<(f32, Vector2<f32>)> = (0..water.len())
+            .into_par_iter()
+            .map(|index| {
+                // The flow coming in from each neighbour's pipe pointing at this cell
+                let [left, right, top, bottom] = neighbours(index, dims, wrap);
+                let inflow_from = |neighbour: Option<usize>, pipe: usize| {
+                    neighbour.map_or(0.0, |neighbour| flux[neighbour][pipe])
+                };
+                let from_left = inflow_from(left, RIGHT);
+                let from_right = inflow_from(right, LEFT);
+                let from_top = inflow_from(top, BOTTOM);
+                let from_bottom = inflow_from(bottom, TOP);
+
+                let outflow = &flux[index];
+                let inflow = from_left + from_right + from_top + from_bottom;
+                let depth = (water[index] + dt * (inflow - outflow.iter().sum::<f32>())).max(0.0);
+
+                // The water passing through the cell in each direction
+                let through_x = (from_left - outflow[LEFT] + outflow[RIGHT] - from_right) / 2.0;
+                let through_y = (from_top - outflow[TOP] + outflow[BOTTOM] - from_bottom) / 2.0;
+
+                // Thin films of water would otherwise get huge velocities - so keep it
+                // to at most a cell per step, which also keeps the advection stable
+                let mean_depth = (water[index] + depth) / 2.0;
+                let velocity = if mean_depth > f32::EPSILON {
+                    (Vector2::new(through_x, through_y) / mean_depth).cap_magnitude(1.0 / dt)
+                } else {
+                    Vector2::zeros()
+                };
+
+                (depth, velocity)
+            })
+            .collect();
+
+        for (index, (depth, velocity)) in updated.into_iter().enumerate() {
+            self.water[index] = depth;
+            self.velocity[index] = velocity;
+        }
+    }
+
+    /// Dissolve terrain into the water or deposit sediment out of it.
+    ///
+    /// Returns the number of cells of `terrain` that were changed.
+    fn erode_deposit(
+        &mut self,
+        terrain: &mut [f32],
+        params: &ShallowWaterParams,
+        hardness: Option<&[f32]>,
+    ) -> usize {
+        let dims = self.dims;
+        let wrap = self.wrap;
+        let snapshot: &[f32] = terrain;
+        let sediment = &self.sediment;
+        let velocity = &self.velocity;
+        let water = &self.water;
+
+        let updated: Vec<(f32, f32)> = (0..snapshot.len())
+            .into_par_iter()
+            .map(|index| {
+                // The sine of the slope the water is flowing down - the walls of a
+                // channel are steep, but only the bed it runs along should count
+                let speed = velocity[index].norm();
+                let gradient = central_gradient(snapshot, index, dims, wrap);
+                let slope = if speed > 0.0 {
+                    (-gradient.dot(&velocity[index]) / speed).max(0.0)
+                } else {
+                    0.0
+                };
+                let tilt = (slope / (1.0 + slope * slope).sqrt()).max(params.min_tilt);
+
+                // Sediment is stored as a height, so how much fits scales with the depth -
+                // up to a limit, otherwise lakes would dig themselves ever deeper
+                let depth = water[index].min(params.max_erosion_depth);
+                let capacity = params.sediment_capacity * tilt * speed * depth;
+                let carried = sediment[index];
+
+                // Use a negative value to indicate erosion
+                let deposit = if capacity > carried {
+                    let softness = hardness.map_or(1.0, |hardness| 1.0 - hardness[index]);
+                    -(capacity - carried) * params.dissolving * softness * params.time_step
+                } else {
+                    (carried - capacity) * params.deposition * params.time_step
+                };
+
+                (deposit, carried - deposit)
+            })
+            .collect();
+
+        let mut changed = 0;
+        for (index, (deposit, carried)) in updated.into_iter().enumerate() {
+            if deposit != 0.0 {
+                terrain[index] += deposit;
+                changed += 1;
+            }
+            self.sediment[index] = carried;
+        }
+
+        changed
+    }
+
+    /// Carry the suspended sediment along with the water.
+    fn advect_sediment(&mut self, dt: f32) {
+        let dims = self.dims;
+        let wrap = self.wrap;
+        let sediment = &self.sediment;
+        let velocity = &self.velocity;
+
+        // Look back along the velocity to find where this cell's sediment came from
+        let advected: Vec<f32> = (0..sediment.len())
+            .into_par_iter()
+            .map(|index| {
+                let x = (index % dims.0) as f32 - velocity[index].x * dt;
+                let y = (index / dims.0) as f32 - velocity[index].y * dt;
+
+                sample_bilinear(sediment, x, y, dims, wrap)
+            })
+            .collect();
+
+        self.sediment = advected;
+    }
+}
+
+/// Get the indices of the left, right, top and bottom neighbours of a cell,
+/// or `None` for those off the edge of the map - unless it wraps around.
+fn neighbours(index: usize, dims: (usize, usize), wrap: bool) -> [Option<usize>; 4] {
+    let x = index % dims.0;
+    let y = index / dims.0;
+
+    if wrap {
+        let row = y * dims.0;
+        return [
+            Some(row + (x + dims.0 - 1) % dims.0),
+            Some(row + (x + 1) % dims.0),
+            Some((y + dims.1 - 1) % dims.1 * dims.0 + x),
+            Some((y + 1) % dims.1 * dims.0 + x),
+        ];
+    }
+
+    [
+        (x > 0).then(|| index - 1),
+        (x + 1 < dims.0).then(|| index + 1),
+        (y > 0).then(|| index - dims.0),
+        (y + 1 < dims.1).then(|| index + dims.0),
+    ]
+}
+
+/// Get the gradient of the terrain at a cell using central differences.
+fn central_gradient(
+    terrain: &[f32],
+    index: usize,
+    dims: (usize, usize),
+    wrap: bool,
+) -> Vector2<f32> {
+    let [left, right, top, bottom] = neighbours(index, dims, wrap);
+    let height = |neighbour: Option<usize>| terrain[neighbour.unwrap_or(index)];
+
+    Vector2::new(
+        (height(right) - height(left)) / 2.0,
+        (height(bottom) - height(top)) / 2.0,
+    )
+}
+
+/// Bilinearly sample a grid at a point, clamping it to the edges - or wrapping it
+/// around them if `wrap` is set.
+fn sample_bilinear(grid: &[f32], x: f32, y: f32, dims: (usize, usize), wrap: bool) -> f32 {
+    let (x, y) = if wrap {
+        // Rounding can leave a tiny negative coordinate on the far edge, so bring it back to 0
+        let wrap = |value: f32, size: usize| {
+            let value = value.rem_euclid(size as f32);
+            if value < size as f32 {
+                value
+            } else {
+                0.0
+            }
+        };
+        (wrap(x, dims.0), wrap(y, dims.1))
+    } else {
+        (
+            x.clamp(0.0, (dims.0 - 1) as f32),
+            y.clamp(0.0, (dims.1 - 1) as f32),
+        )
+    };
+
+    let x0 = x.floor() as usize;
+    let y0 = y.floor() as usize;
+    let (x1, y1) = if wrap {
+        ((x0 + 1) % dims.0, (y0 + 1) % dims.1)
+    } else {
+        ((x0 + 1).min(dims.0 - 1), (y0 + 1).min(dims.1 - 1))
+    };
+    let u = x.fract();
+    let v = y.fract();
+
+    let nw = grid[y0 * dims.0 + x0];
+    let ne = grid[y0 * dims.0 + x1];
+    let sw = grid[y1 * dims.0 + x0];
+    let se = grid[y1 * dims.0 + x1];
+
+    (nw * (1.0 - u) * (1.0 - v)) + (ne * u * (1.0 - v)) + (sw * (1.0 - u) * v) + (se * u * v)
+}
@@ -0,0 +1,837 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::brush::Brush;
+use crate::changes::ChangeBuffer;
+use crate::checkpoint::Checkpoint;
+use crate::create_raindrops;
+use crate::error::Result;
+use crate::layers::Strata;
+use crate::params::{ErosionModel, ErosionParams};
+use crate::precipitation::{orographic_rain, RainSampler};
+use crate::pyramid::{downsample, level_params, upsample};
+use crate::raindrop::Raindrop;
+use crate::shallow_water::ShallowWater;
+use crate::thermal::thermal_erode;
+use crate::tiles::Tiling;
+
+/// How many `Raindrop`s add their changes up in the same buffer before it's applied.
+const DROPS_PER_CHUNK: usize = 512;
+
+/// One of the coarser copies of the heightmap eroded by `ErosionSimulator::run_pyramid`.
+struct PyramidLevel {
+    // The heights, downsampled from the level below
+    heights: Vec<f32>,
+    // The dimensions of the level as a tuple of `(x: usize, y: usize)`
+    dims: (usize, usize),
+    // How much every cell resists erosion, if anything does
+    resistance: Option<Vec<f32>>,
+    // How much rain every cell gets, if it isn't even
+    precipitation: Option<Vec<f32>>,
+}
+
+/// A headless hydraulic erosion simulation, running either the droplet or the
+/// shallow-water model.
+///
+/// Owns the heightmap, its dimensions and the `ErosionParams`, so terrain
+/// can be eroded without a Godot runtime. `TerrainMesh` drives one of these from
+/// its physics thread.
+///
+/// All randomness comes from a single RNG seeded with `seed`, and the parallel
+/// work is merged back in a fixed order - so the same seed, heightmap and
+/// parameters always produce a bit-identical result, no matter how many threads
+/// rayon uses.
+#[derive(Debug, Clone)]
+pub struct ErosionSimulator {
+    /// The heightmap being eroded, stored row by row.
+    pub heightmap: Vec<f32>,
+    /// The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`.
+    pub dims: (usize, usize),
+    /// The parameters every `Raindrop` is simulated with.
+    pub params: ErosionParams,
+    /// How much material has been removed from every cell in total.
+    pub erosion: Vec<f32>,
+    /// How much material has been laid down on every cell in total.
+    pub deposition: Vec<f32>,
+    /// How much water flows through every cell - a running total that fades by
+    /// `params.flow_memory` each iteration, so rivers stand out and damp ground dries off.
+    pub flow: Vec<f32>,
+    // How many iterations have been run so far
+    iteration: usize,
+    // The seed the RNG was created from
+    seed: u64,
+    // The RNG used for everything random in the simulation
+    rng: ChaCha8Rng,
+    // The water, flux and sediment of the shallow-water model, once it has been run
+    shallow_water: Option<ShallowWater>,
+    // The brush every `Raindrop` erodes and deposits with, rebuilt when the diameter changes
+    brush: Brush,
+    // The rock hardness of every cell, from 0.0 (soft) to 1.0 (can't be eroded)
+    hardness: Option<Vec<f32>>,
+    // The material layers under every cell, once `params.layers` has been used
+    strata: Option<Strata>,
+    // How much rain falls on every cell relative to the others, if it isn't even
+    precipitation: Option<Vec<f32>>,
+    // Where the rain falls, worked out from `precipitation` and the `rain_` parameters
+    rain: Option<Rain>,
+}
+
+/// How far a simulation has got - its iteration count, RNG and shallow-water state - so
+/// another `ErosionSimulator` over the same terrain can carry on exactly where it stopped.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// How many iterations have been run.
+    pub iteration: usize,
+    /// The seed the RNG was started from.
+    pub seed: u64,
+    /// The RNG, part way through its stream.
+    pub rng: ChaCha8Rng,
+    /// The water, flux and sediment of the shallow-water model, if it has been run.
+    pub shallow_water: Option<ShallowWater>,
+}
+
+/// Where the rain falls, kept between iterations as it's slow to work out on large maps.
+#[derive(Debug, Clone)]
+struct Rain {
+    // The `rain_` parameters the weights were worked out with, and whether the map wrapped
+    params: (f32, f32, f32, bool),
+    // The iteration the weights were worked out on, if they follow the terrain
+    iteration: Option<usize>,
+    // How much rain falls on every cell, averaging out to 1.0
+    weights: Vec<f32>,
+    // Picks where `Raindrop`s start, never on the last row or column unless the map wraps -
+    // `None` if no cell they can start on gets any rain
+    sampler: Option<RainSampler>,
+}
+
+impl ErosionSimulator {
+    /// Create a new `ErosionSimulator` over the given heightmap using the default parameters
+    /// and a seed of `0`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heightmap doesn't contain exactly `dims.0 * dims.1` values.
+    pub fn new(heightmap: Vec<f32>, dims: (usize, usize)) -> Self {
+        assert_eq!(
+            heightmap.len(),
+            dims.0 * dims.1,
+            "heightmap doesn't match its dimensions"
+        );
+
+        let cells = heightmap.len();
+
+        ErosionSimulator {
+            heightmap,
+            dims,
+            params: ErosionParams::default(),
+            erosion: vec![0.0; cells],
+            deposition: vec![0.0; cells],
+            flow: vec![0.0; cells],
+            iteration: 0,
+            seed: 0,
+            rng: ChaCha8Rng::seed_from_u64(0),
+            brush: Brush::new(ErosionParams::default().diameter, dims, false),
+            shallow_water: None,
+            hardness: None,
+            strata: None,
+            precipitation: None,
+            rain: None,
+        }
+    }
+
+    /// Restart the RNG from the given seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// The seed the RNG was last started from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The number of iterations run so far.
+    pub fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    /// How far the simulation has got, to `resume` from later.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            iteration: self.iteration,
+            seed: self.seed,
+            rng: self.rng.clone(),
+            shallow_water: self.shallow_water.clone(),
+        }
+    }
+
+    /// Carry on from where another simulation over the same terrain got to, with the same
+    /// iteration count, RNG and water.
+    ///
+    /// Shallow water for a map of a different size is left behind.
+    pub fn resume(&mut self, progress: Progress) {
+        self.iteration = progress.iteration;
+        self.seed = progress.seed;
+        self.rng = progress.rng;
+        self.shallow_water = progress
+            .shallow_water
+            .filter(|water| water.dims() == self.dims);
+    }
+
+    /// Capture everything needed to carry on from here later, even in another process.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            params: self.params.clone(),
+            dims: self.dims,
+            heightmap: self.heightmap.clone(),
+            erosion: self.erosion.clone(),
+            deposition: self.deposition.clone(),
+            flow: self.flow.clone(),
+            hardness: self.hardness.clone(),
+            precipitation: self.precipitation.clone(),
+            strata: self.strata.clone(),
+            progress: self.progress(),
+        }
+    }
+
+    /// Create an `ErosionSimulator` that carries on exactly where the one a checkpoint
+    /// was taken from left off - running both gives bit-identical results, since what
+    /// the checkpoint doesn't hold, like the orographic rain, is worked out again from
+    /// what it does.
+    pub fn from_checkpoint(checkpoint: Checkpoint) -> Self {
+        let mut simulator = ErosionSimulator::new(checkpoint.heightmap, checkpoint.dims);
+        simulator.params = checkpoint.params;
+        simulator.erosion = checkpoint.erosion;
+        simulator.deposition = checkpoint.deposition;
+        simulator.flow = checkpoint.flow;
+        simulator.hardness = checkpoint.hardness;
+        simulator.precipitation = checkpoint.precipitation;
+        simulator.strata = checkpoint.strata;
+        simulator.resume(checkpoint.progress);
+
+        simulator
+    }
+
+    /// Save a checkpoint to a file - see `Checkpoint::save`.
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.checkpoint().save(path)
+    }
+
+    /// Load an `ErosionSimulator` from a checkpoint file - see `Checkpoint::load`.
+    pub fn load_checkpoint(path: impl AsRef<Path>) -> Result<Self> {
+        Checkpoint::load(path).map(ErosionSimulator::from_checkpoint)
+    }
+
+    /// Set the rock hardness of every cell, from `0.0` (soft) to `1.0` (can't be eroded),
+    /// or `None` to make everything equally soft. Values outside that range are clamped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the hardness map doesn't match the heightmap's dimensions.
+    pub fn set_hardness(&mut self, mut hardness: Option<Vec<f32>>) {
+        if let Some(hardness) = &mut hardness {
+            assert_eq!(
+                hardness.len(),
+                self.heightmap.len(),
+                "hardness map doesn't match the heightmap"
+            );
+
+            hardness
+                .par_iter_mut()
+                .for_each(|hardness| *hardness = hardness.clamp(0.0, 1.0));
+        }
+
+        self.hardness = hardness;
+    }
+
+    /// The rock hardness of every cell, if there is a hardness map.
+    pub fn hardness(&self) -> Option<&[f32]> {
+        self.hardness.as_deref()
+    }
+
+    /// The material layers under every cell, if `params.layers` has any.
+    pub fn strata(&self) -> Option<&Strata> {
+        self.strata.as_ref()
+    }
+
+    /// Carry on from previously eroded layers, or `None` to start again from `params.layers`.
+    ///
+    /// The layers are ignored if they were built from different `params.layers`.
+    pub fn set_strata(&mut self, strata: Option<Strata>) {
+        self.strata = strata;
+    }
+
+    /// Set how much rain falls on every cell relative to the others, or `None` for
+    /// the same everywhere. Negative values count as `0.0`.
+    ///
+    /// This is combined with the rain from the `rain_elevation` and `rain_windward`
+    /// parameters, which are worked out from the terrain when they change.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the precipitation map doesn't match the heightmap's dimensions.
+    pub fn set_precipitation(&mut self, precipitation: Option<Vec<f32>>) {
+        if let Some(precipitation) = &precipitation {
+            assert_eq!(
+                precipitation.len(),
+                self.heightmap.len(),
+                "precipitation map doesn't match the heightmap"
+            );
+        }
+
+        self.precipitation = precipitation;
+        self.rain = None;
+    }
+
+    /// How much rain falls on every cell relative to the others, if it isn't even.
+    pub fn precipitation(&self) -> Option<&[f32]> {
+        self.precipitation.as_deref()
+    }
+
+    /// The state of the shallow-water model, if it has been run.
+    pub fn shallow_water(&self) -> Option<&ShallowWater> {
+        self.shallow_water.as_ref()
+    }
+
+    /// Run a single iteration of whichever model `params.model` selects.
+    ///
+    /// Every `params.thermal_interval` iterations this is followed by a thermal pass.
+    /// The `flow` map fades a little before the iteration adds the water that ran through
+    /// every cell. The total `erosion` and `deposition` are then updated with how much every cell
+    /// went down or up. If there's a hardness map and `params.hardening` is set, it's
+    /// updated to follow the material that was eroded and deposited, as are the
+    /// material layers.
+    ///
+    /// # Returns
+    ///
+    /// The number of changes made to the heightmap.
+    pub fn step(&mut self) -> usize {
+        self.update_strata_layers();
+
+        // Keep the old heights around to see what changed
+        let before = self.heightmap.clone();
+
+        // Fade out the water from earlier iterations
+        let memory = self.params.flow_memory.clamp(0.0, 1.0);
+        self.flow.par_iter_mut().for_each(|flow| *flow *= memory);
+
+        let changes = match self.params.model {
+            ErosionModel::Droplet => self.droplet_step(),
+            ErosionModel::ShallowWater => self.shallow_water_step(),
+        };
+
+        self.iteration += 1;
+
+        // Interleave thermal weathering with the erosion
+        let interval = self.params.thermal_interval as usize;
+        if interval > 0 && self.iteration.is_multiple_of(interval) {
+            self.thermal_pass();
+        }
+
+        self.accumulate(&before);
+        if self.params.hardening > 0.0 {
+            self.update_hardness(&before);
+        }
+        if let Some(strata) = &mut self.strata {
+            strata.apply_changes(&before, &self.heightmap);
+        }
+
+        changes
+    }
+
+    /// Add how much every cell went down or up since `before` to the total `erosion`
+    /// and `deposition`.
+    fn accumulate(&mut self, before: &[f32]) {
+        self.erosion
+            .par_iter_mut()
+            .zip(self.deposition.par_iter_mut())
+            .zip(self.heightmap.par_iter().zip(before))
+            .for_each(|((erosion, deposition), (after, before))| {
+                let change = after - before;
+
+                if change < 0.0 {
+                    *erosion -= change;
+                } else {
+                    *deposition += change;
+                }
+            });
+    }
+
+    /// Set the total `erosion` and `deposition` back to zero.
+    pub fn reset_accumulation(&mut self) {
+        self.erosion.fill(0.0);
+        self.deposition.fill(0.0);
+    }
+
+    /// Work out where the rain falls if the `rain_` parameters have changed, or it hasn't
+    /// been yet. Orographic rain depends on the terrain, so it's worked out again every
+    /// iteration to follow it as it erodes.
+    fn update_rain(&mut self) {
+        let params = (
+            self.params.rain_elevation,
+            self.params.rain_windward,
+            self.params.rain_wind_direction,
+            self.params.wraps(),
+        );
+        let (elevation, windward, wind_direction, wrap) = params;
+        let orographic = elevation != 0.0 || windward != 0.0;
+
+        if self.precipitation.is_none() && !orographic {
+            self.rain = None;
+            return;
+        }
+        let iteration = orographic.then_some(self.iteration);
+        if self
+            .rain
+            .as_ref()
+            .is_some_and(|rain| rain.params == params && rain.iteration == iteration)
+        {
+            return;
+        }
+
+        let mut weights = if orographic {
+            orographic_rain(
+                &self.heightmap,
+                self.dims,
+                elevation,
+                windward,
+                wind_direction,
+                wrap,
+            )
+        } else {
+            vec![1.0; self.heightmap.len()]
+        };
+        if let Some(precipitation) = &self.precipitation {
+            weights
+                .par_iter_mut()
+                .zip(precipitation)
+                .for_each(|(weight, precipitation)| *weight *= precipitation.max(0.0));
+        }
+
+        // Even the rain out to an average of 1.0, so the total amount stays the same
+        let mean = weights.iter().map(|&weight| weight as f64).sum::<f64>() / weights.len() as f64;
+        if mean > 0.0 {
+            weights
+                .par_iter_mut()
+                .for_each(|weight| *weight = (*weight as f64 / mean) as f32);
+        }
+
+        // Raindrops can't start on the last row or column, unless the map wraps
+        let dims = self.dims;
+        let spawnable: Vec<f32> = weights
+            .par_iter()
+            .enumerate()
+            .map(|(index, &weight)| {
+                let inside = index % dims.0 < dims.0 - 1 && index / dims.0 < dims.1 - 1;
+                if inside || wrap {
+                    weight
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        self.rain = Some(Rain {
+            params,
+            iteration,
+            weights,
+            sampler: RainSampler::new(&spawnable),
+        });
+    }
+
+    /// Start the material layers from `params.layers` if they haven't been, or if
+    /// the layers have changed since, and drop them if there aren't any.
+    ///
+    /// Every iteration does this first, so it only needs calling to see the layers an
+    /// iteration will start from.
+    pub fn update_strata_layers(&mut self) {
+        let layers = &self.params.layers;
+
+        if layers.is_empty() {
+            self.strata = None;
+        } else if self
+            .strata
+            .as_ref()
+            .is_none_or(|strata| strata.layers() != layers.as_slice())
+        {
+            self.strata = Some(Strata::new(layers, self.heightmap.len()));
+        }
+    }
+
+    /// Harden cells that have been eroded and soften cells that have been buried,
+    /// by how much their height changed since `before`.
+    ///
+    /// Stripping away material moves the hardness towards `1.0`, as if digging into
+    /// the rock underneath, while fresh sediment moves it towards `0.0`.
+    fn update_hardness(&mut self, before: &[f32]) {
+        let hardening = self.params.hardening;
+        let Some(hardness) = self.hardness.as_mut() else {
+            return;
+        };
+
+        hardness
+            .par_iter_mut()
+            .zip(self.heightmap.par_iter().zip(before))
+            .for_each(|(hardness, (after, before))| {
+                let change = after - before;
+                let amount = (change.abs() * hardening).min(1.0);
+
+                if change < 0.0 {
+                    *hardness += (1.0 - *hardness) * amount;
+                } else {
+                    *hardness *= 1.0 - amount;
+                }
+            });
+    }
+
+    /// Spawn `params.drop_count` `Raindrop`s, simulate them in parallel against the
+    /// current heightmap, then apply their changes.
+    ///
+    /// Returns the number of changes applied - every cell changed by a chunk of drops
+    /// counts once for that chunk.
+    fn droplet_step(&mut self) -> usize {
+        self.update_rain();
+
+        let wrap = self.params.wraps();
+        if self.brush.diameter() != self.params.diameter || self.brush.wrap() != wrap {
+            self.brush = Brush::new(self.params.diameter, self.dims, wrap);
+        }
+
+        // No rain falls anywhere a drop can start, as opposed to no map saying where it falls
+        let drop_count = match &self.rain {
+            Some(rain) if rain.sampler.is_none() => 0,
+            _ => self.params.drop_count,
+        };
+
+        // Create Raindrops
+        let mut drops: Vec<Raindrop> = create_raindrops(
+            drop_count,
+            self.params.starting_mass,
+            self.dims,
+            wrap,
+            self.rain.as_ref().and_then(|rain| rain.sampler.as_ref()),
+            &mut self.rng,
+        );
+
+        if self.params.tiled {
+            let reach =
+                self.params.lifetime as usize + (self.params.diameter / 2.0).ceil() as usize;
+            let tiling = Tiling::new(self.dims, reach, wrap);
+
+            // A wrapping map too small for two tiles across and down runs untiled instead
+            if !tiling.is_empty() {
+                return self.tiled_droplet_step(tiling, drops);
+            }
+        }
+
+        let resistance = resistance(self.hardness.as_deref(), self.strata.as_ref());
+
+        // Simulate Raindrops in fixed-size chunks, each adding up its changes in buffers of
+        // its own. Chunks don't depend on the number of threads and `collect` keeps them in
+        // order, so the changes are applied deterministically
+        let cells = self.heightmap.len();
+        let chunks: Vec<_> = drops
+            .par_chunks_mut(DROPS_PER_CHUNK)
+            .map(|drops| {
+                let mut changes = ChangeBuffer::new(cells);
+                let mut flow = ChangeBuffer::new(cells);
+                for drop in drops {
+                    drop.simulate(
+                        &self.heightmap,
+                        self.dims,
+                        &self.params,
+                        &self.brush,
+                        resistance.as_deref(),
+                        &mut changes,
+                        &mut flow,
+                    );
+                }
+
+                (changes.drain(), flow.drain())
+            })
+            .collect();
+
+        // Update the heightmap with the changes, and the flow with where the water went
+        let mut count = 0;
+        for (changes, flow) in chunks.iter() {
+            for (change, index) in changes.iter() {
+                self.heightmap[*index] += change;
+            }
+            for (water, index) in flow.iter() {
+                self.flow[*index] += water;
+            }
+
+            count += changes.len();
+        }
+
+        count
+    }
+
+    /// Simulate `Raindrop`s tile by tile, with each one eroding the terrain as the
+    /// ones before it in the same tile left it. See `Tiling` for how tiles run in parallel.
+    ///
+    /// Returns the number of changes applied - every cell changed by a drop counts once.
+    fn tiled_droplet_step(&mut self, tiling: Tiling, drops: Vec<Raindrop>) -> usize {
+        let dims = self.dims;
+        let wrap = self.params.wraps();
+        let resistance = resistance(self.hardness.as_deref(), self.strata.as_ref());
+        let resistance = resistance.as_deref();
+
+        // Hand every drop to the tile it starts in, keeping them in the order they were made
+        let mut tiles: Vec<Vec<Raindrop>> = (0..tiling.len()).map(|_| Vec::new()).collect();
+        for drop in drops {
+            let tile = tiling.tile_at(drop.position.x as usize, drop.position.y as usize);
+            tiles[tile].push(drop);
+        }
+
+        let mut count = 0;
+        for colour in 0..4 {
+            let batch: Vec<(usize, Vec<Raindrop>)> = tiles
+                .iter_mut()
+                .enumerate()
+                .filter(|(tile, drops)| tiling.colour(*tile) == colour && !drops.is_empty())
+                .map(|(tile, drops)| (tile, std::mem::take(drops)))
+                .collect();
+
+            // The windows of tiles with the same colour never overlap, so they can all be
+            // eroded at once and copied back in any order
+            let results: Vec<_> = batch
+                .into_par_iter()
+                .map(|(tile, drops)| {
+                    let window = tiling.window(tile);
+                    let mut heights = window.copy_from(&self.heightmap, dims);
+                    let resistance = resistance.map(|map| window.copy_from(map, dims));
+                    // Drops never reach the edge of a window unless it covers the whole
+                    // width or height of the map, so it only needs to wrap like the map does
+                    let brush = Brush::new(self.params.diameter, window.dims, wrap);
+
+                    let mut changes = ChangeBuffer::new(window.cells());
+                    let mut flow = ChangeBuffer::new(window.cells());
+                    let mut count = 0;
+
+                    for mut drop in drops {
+                        drop.position.x =
+                            (drop.position.x - window.origin.0 as f32).rem_euclid(dims.0 as f32);
+                        drop.position.y =
+                            (drop.position.y - window.origin.1 as f32).rem_euclid(dims.1 as f32);
+
+                        drop.simulate(
+                            &heights,
+                            window.dims,
+                            &self.params,
+                            &brush,
+                            resistance.as_deref(),
+                            &mut changes,
+                            &mut flow,
+                        );
+
+                        // Apply the changes straight away so the next drop sees them
+                        count += changes.drain_into(&mut heights);
+                    }
+
+                    (window, heights, flow.drain(), count)
+                })
+                .collect();
+
+            for (window, heights, flow, changes) in results {
+                window.copy_to(&heights, &mut self.heightmap, dims);
+                for (water, index) in flow {
+                    self.flow[window.to_map(index, dims)] += water;
+                }
+
+                count += changes;
+            }
+        }
+
+        count
+    }
+
+    /// Advance the shallow-water model by `params.shallow_water.steps` time steps,
+    /// starting it with dry terrain if it hasn't been run before.
+    fn shallow_water_step(&mut self) -> usize {
+        self.update_rain();
+
+        let dims = self.dims;
+        let water = self
+            .shallow_water
+            .get_or_insert_with(|| ShallowWater::new(dims));
+        water.set_wrap(self.params.wraps());
+        let resistance = resistance(self.hardness.as_deref(), self.strata.as_ref());
+
+        let time_step = self.params.shallow_water.time_step;
+
+        (0..self.params.shallow_water.steps)
+            .map(|_| {
+                let changes = water.step(
+                    &mut self.heightmap,
+                    &self.params.shallow_water,
+                    resistance.as_deref(),
+                    self.rain.as_ref().map(|rain| rain.weights.as_slice()),
+                );
+
+                // The water passing through each cell is its depth times its speed
+                self.flow
+                    .par_iter_mut()
+                    .zip(water.water.par_iter().zip(water.velocity()))
+                    .for_each(|(flow, (depth, velocity))| {
+                        *flow += depth * velocity.norm() * time_step
+                    });
+
+                changes
+            })
+            .sum()
+    }
+
+    /// Run a single thermal weathering pass on its own, using the `thermal_` parameters.
+    ///
+    /// # Returns
+    ///
+    /// The total amount of material moved.
+    pub fn thermal_pass(&mut self) -> f32 {
+        thermal_erode(
+            &mut self.heightmap,
+            self.dims,
+            self.params.thermal_talus,
+            self.params.thermal_rate,
+            self.params.wraps(),
+        )
+    }
+
+    /// Erode coarser copies of the heightmap before the full-resolution one, from the
+    /// coarsest up, using the `pyramid_` parameters - so the big valleys form first and
+    /// the full-resolution iterations after it only have to carve the detail.
+    ///
+    /// # Returns
+    ///
+    /// The number of changes made over every level.
+    ///
+    /// # Explanation
+    ///
+    /// Each level halves the resolution of the one below it with `downsample` - the
+    /// precipitation too, and the resistance of the hardness and material layers as
+    /// they are now, which becomes the level's hardness - and is eroded for
+    /// `pyramid_iterations` iterations with the parameters scaled to it by
+    /// `level_params`. What a level changed, rather than its heights, is then upsampled
+    /// and added onto the level below before that one is eroded, so the finer level
+    /// keeps the detail the coarse one never had. The changes from the lowest coarse
+    /// level end up on the heightmap itself, and count towards `erosion` and
+    /// `deposition` like an iteration's would.
+    pub fn run_pyramid(&mut self) -> usize {
+        self.update_strata_layers();
+        let resistance = resistance(self.hardness.as_deref(), self.strata.as_ref());
+
+        // Halve the heightmap over and over, stopping early if it gets too small to erode
+        let mut pyramid: Vec<PyramidLevel> = Vec::new();
+        while pyramid.len() < self.params.pyramid_levels as usize {
+            let (heights, dims, resistance, precipitation) = match pyramid.last() {
+                Some(level) => (
+                    level.heights.as_slice(),
+                    level.dims,
+                    level.resistance.as_deref(),
+                    level.precipitation.as_deref(),
+                ),
+                None => (
+                    self.heightmap.as_slice(),
+                    self.dims,
+                    resistance.as_deref(),
+                    self.precipitation.as_deref(),
+                ),
+            };
+            if dims.0 < 4 || dims.1 < 4 {
+                break;
+            }
+
+            let (heights, coarse_dims) = downsample(heights, dims);
+            pyramid.push(PyramidLevel {
+                heights,
+                dims: coarse_dims,
+                resistance: resistance.map(|map| downsample(map, dims).0),
+                precipitation: precipitation.map(|map| downsample(map, dims).0),
+            });
+        }
+
+        let finer_dims: Vec<_> = std::iter::once(self.dims)
+            .chain(pyramid.iter().map(|level| level.dims))
+            .collect();
+
+        // Erode from the coarsest level down, carrying the changes from each to the next
+        let mut changes = 0;
+        let mut carried: Option<Vec<f32>> = None;
+        for (index, level) in pyramid.into_iter().enumerate().rev() {
+            let mut heights = level.heights.clone();
+            if let Some(carried) = &carried {
+                heights
+                    .par_iter_mut()
+                    .zip(carried)
+                    .for_each(|(height, change)| *height += change);
+            }
+
+            let mut simulator = ErosionSimulator::new(heights, level.dims);
+            simulator.params = level_params(&self.params, index as u32 + 1);
+            simulator.set_hardness(level.resistance);
+            simulator.set_precipitation(level.precipitation);
+            simulator.reseed(self.rng.gen());
+            for _ in 0..self.params.pyramid_iterations {
+                changes += simulator.step();
+            }
+
+            let change: Vec<f32> = simulator
+                .heightmap
+                .par_iter()
+                .zip(&level.heights)
+                .map(|(after, before)| after - before)
+                .collect();
+            carried = Some(upsample(&change, level.dims, finer_dims[index]));
+        }
+
+        if let Some(carried) = carried {
+            let before = self.heightmap.clone();
+            self.heightmap
+                .par_iter_mut()
+                .zip(&carried)
+                .for_each(|(height, change)| *height += change);
+
+            self.accumulate(&before);
+            if self.params.hardening > 0.0 {
+                self.update_hardness(&before);
+            }
+            if let Some(strata) = &mut self.strata {
+                strata.apply_changes(&before, &self.heightmap);
+            }
+        }
+
+        changes
+    }
+
+    /// Run `iterations` iterations back to back.
+    pub fn run(&mut self, iterations: usize) {
+        for _ in 0..iterations {
+            self.step();
+        }
+    }
+}
+
+/// Combine the hardness map with the erodibility of the exposed material layers into
+/// how much every cell resists erosion, from `0.0` (soft) to `1.0` (can't be eroded).
+fn resistance<'a>(hardness: Option<&'a [f32]>, strata: Option<&Strata>) -> Option<Cow<'a, [f32]>> {
+    let Some(strata) = strata else {
+        return hardness.map(Cow::Borrowed);
+    };
+
+    let mut resistance = strata.resistance();
+    if let Some(hardness) = hardness {
+        // Both have to let material through for it to be eroded
+        resistance
+            .par_iter_mut()
+            .zip(hardness)
+            .for_each(|(resistance, hardness)| {
+                *resistance = 1.0 - (1.0 - *resistance) * (1.0 - hardness)
+            });
+    }
+
+    Some(Cow::Owned(resistance))
+}
@@ -0,0 +1,100 @@
+use nalgebra::{Vector2, Vector3};
+use rayon::prelude::*;
+
+/// Maps describing the shape of the terrain, baked from its heightmap so shaders and
+/// other engines don't have to work them out from the heights.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SurfaceMaps {
+    /// A tangent-space normal for every cell, with `x` along the map's `+x`, `y` up the
+    /// image (towards row `0`, as OpenGL and Godot expect) and `z` out of the surface.
+    pub normals: Vec<[f32; 3]>,
+    /// How steep every cell is, as the height difference per cell.
+    pub slope: Vec<f32>,
+    /// How convex every cell is - positive on ridges and peaks, negative in valleys and
+    /// pits, and `0.0` on flat ground and even slopes.
+    pub curvature: Vec<f32>,
+}
+
+impl SurfaceMaps {
+    /// Bake the maps for a heightmap with dimensions `dims`.
+    ///
+    /// `strength` exaggerates (or flattens) the normals without changing the slope,
+    /// and with `wrap` the neighbours of cells on an edge are on the opposite one.
+    pub fn bake(heightmap: &[f32], dims: (usize, usize), strength: f32, wrap: bool) -> Self {
+        let (normals, (slope, curvature)) = (0..heightmap.len())
+            .into_par_iter()
+            .map(|index| {
+                let x = index % dims.0;
+                let y = index / dims.0;
+                let height = |x: usize, y: usize| heightmap[y * dims.0 + x];
+
+                let (left, right, up, down) = neighbours(x, y, dims, wrap);
+
+                // Central differences, one-sided where an edge holds a neighbour in place
+                let across = if wrap { 2 } else { right - left };
+                let down_across = if wrap { 2 } else { down - up };
+                let gradient = Vector2::new(
+                    (height(right, y) - height(left, y)) / across.max(1) as f32,
+                    (height(x, down) - height(x, up)) / down_across.max(1) as f32,
+                );
+
+                // Rows run down the image, so the normal's y is flipped
+                let normal =
+                    Vector3::new(-gradient.x * strength, gradient.y * strength, 1.0).normalize();
+
+                // The negated Laplacian is positive where the cell stands above its neighbours
+                let curvature = 4.0 * height(x, y)
+                    - height(left, y)
+                    - height(right, y)
+                    - height(x, up)
+                    - height(x, down);
+
+                ([normal.x, normal.y, normal.z], (gradient.norm(), curvature))
+            })
+            .unzip();
+
+        SurfaceMaps {
+            normals,
+            slope,
+            curvature,
+        }
+    }
+
+    /// The normals packed into colours from `0.0` to `1.0`, as normal map textures
+    /// store them - flat ground is `(0.5, 0.5, 1.0)`.
+    pub fn normal_colours(&self) -> Vec<[f32; 3]> {
+        self.normals
+            .par_iter()
+            .map(|normal| normal.map(|axis| axis * 0.5 + 0.5))
+            .collect()
+    }
+}
+
+/// The columns left and right of cell `(x, y)` and the rows above and below it, as
+/// `(left, right, up, down)`.
+///
+/// Neighbours off an edge are on the opposite edge if `wrap`, and are held on the edge
+/// itself otherwise - so a cell on the edge of a map that doesn't wrap is its own
+/// neighbour on that side.
+pub fn neighbours(
+    x: usize,
+    y: usize,
+    dims: (usize, usize),
+    wrap: bool,
+) -> (usize, usize, usize, usize) {
+    if wrap {
+        (
+            (x + dims.0 - 1) % dims.0,
+            (x + 1) % dims.0,
+            (y + dims.1 - 1) % dims.1,
+            (y + 1) % dims.1,
+        )
+    } else {
+        (
+            x.saturating_sub(1),
+            (x + 1).min(dims.0 - 1),
+            y.saturating_sub(1),
+            (y + 1).min(dims.1 - 1),
+        )
+    }
+}
@@ -1,28 +1,53 @@
-use godot::classes::{ImageTexture, RenderingServer};
+// The godot macros generate functions returning large `CallError`s - nothing we can do about that
+#![allow(clippy::result_large_err)]
+
+use godot::classes::{ImageTexture, ProjectSettings, RenderingServer};
 use godot::{
     classes::{
-        image::Format, CompressedTexture2D, IMeshInstance3D, Image, MeshInstance3D, ResourceLoader,
-        Shader, ShaderMaterial,
+        image::Format, IMeshInstance3D, Image, MeshInstance3D, ResourceLoader, Shader,
+        ShaderMaterial, Texture2D,
     },
     obj::NewGd,
     prelude::*,
 };
-use rayon::prelude::*;
 
+use std::path::Path;
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
 use std::sync::RwLock;
-use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 
 use lazy_static::lazy_static;
 
-use crate::create_raindrops;
-use crate::raindrop::Raindrop;
+use crate::generate::{generate, TerrainParams};
+use crate::history::{History, Pass};
+use crate::io;
+use crate::layers::Strata;
+use crate::mesh::{MeshOptions, TriangleMesh};
+use crate::params::{
+    Boundary, ErosionModel, ErosionParams, LayerParams, ShallowWaterParams, PRESETS,
+};
+use crate::simulator::{ErosionSimulator, Progress};
+use crate::surface::SurfaceMaps;
 
 lazy_static! {
     static ref IMAGE_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref EROSION_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref DEPOSITION_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref FLOW_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref NORMAL_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref SLOPE_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
+    static ref CURVATURE_ID: RwLock<Rid> = RwLock::new(Rid::new(0));
     static ref DIMS: RwLock<(usize, usize)> = RwLock::new((0, 0));
     static ref TEXTURE: RwLock<Vec<f32>> = RwLock::new(Vec::with_capacity(0));
+    static ref HARDNESS: RwLock<Option<Vec<f32>>> = RwLock::new(None);
+    static ref STRATA: RwLock<Option<Strata>> = RwLock::new(None);
+    static ref PRECIPITATION: RwLock<Option<Vec<f32>>> = RwLock::new(None);
+    static ref EROSION: RwLock<Vec<f32>> = RwLock::new(Vec::with_capacity(0));
+    static ref DEPOSITION: RwLock<Vec<f32>> = RwLock::new(Vec::with_capacity(0));
+    static ref FLOW: RwLock<Vec<f32>> = RwLock::new(Vec::with_capacity(0));
+    static ref PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+    static ref HISTORY: Mutex<History> = Mutex::new(History::new(0));
     static ref THREAD: Mutex<Option<(std::thread::JoinHandle<()>, Sender<()>)>> = Mutex::new(None);
     static ref MOUSE_POS: RwLock<Vector2> = RwLock::new(Vector2::new(0.0, 0.0));
     static ref DRAGGING: RwLock<bool> = RwLock::new(false);
@@ -32,6 +57,30 @@ lazy_static! {
 #[class(base=MeshInstance3D)]
 struct TerrainMesh {
     base: Base<MeshInstance3D>,
+    /// The heightmap loaded when the scene starts - a `res://`, `user://` or absolute path to an
+    /// EXR, 16-bit PNG, `.r16`/`.raw` or ESRI ASCII grid file.
+    #[export(file = "*.exr,*.png,*.r16,*.raw,*.asc")]
+    input_path: GString,
+    /// Where the eroded heightmap is written when the simulation stops - as raw floats for
+    /// `.f32` files and as an EXR otherwise. The sediment, flow and layer maps are written
+    /// next to it, with `_sediment`, `_flow` and `_layers` added to its name.
+    #[export(file = "*.exr,*.f32")]
+    output_path: GString,
+    /// What every height read by `load_heightmap` is multiplied by - PNGs and `.r16`/`.raw`
+    /// files come in from `0.0` to `1.0`, and ASCII grids in their own units.
+    #[var]
+    input_height_scale: f32,
+    /// Stretch the heights read by `load_heightmap` so the lowest lands on `input_range.x`
+    /// and the highest on `input_range.y`, instead of multiplying them by
+    /// `input_height_scale`.
+    #[var]
+    normalize_input: bool,
+    /// The lowest and highest heights `normalize_input` stretches loaded heightmaps to.
+    #[var]
+    input_range: Vector2,
+    /// Which erosion model to run - `"droplet"` or `"shallow_water"`.
+    #[var]
+    model: GString,
     #[var]
     gravity: f32,
     /// Carrying capcity of the `Raindrop` - how much sediment it can carry.
@@ -52,123 +101,211 @@ struct TerrainMesh {
     lifetime: u32,
     #[var]
     starting_mass: f32,
+    /// The fraction of a `Raindrop`'s water that evaporates each step.
+    #[var]
+    evaporation: f32,
+    /// `Raindrop`s slower than this die.
+    #[var]
+    min_velocity: f32,
+    /// What happens to `Raindrop`s that reach the edge of the map - `"deposit"`, `"discard"`,
+    /// `"reflect"`, `"clamp"` or `"wrap"`.
+    #[var]
+    boundary: GString,
+    /// How many `Raindrop`s are simulated each iteration.
+    #[var]
+    drop_count: u32,
+    /// Simulate `Raindrop`s tile by tile so each sees the changes made by the ones before
+    /// it, rather than all of them eroding the terrain as it was at the start of the iteration.
+    #[var]
+    tiled: bool,
+    /// How many coarser, downsampled copies of the terrain to erode when the simulation starts
+    /// from scratch, before carrying on at full resolution - `0` turns them off.
+    #[var]
+    pyramid_levels: u32,
+    /// How many iterations to run on each of the coarser copies.
+    #[var]
+    pyramid_iterations: u32,
+    /// The steepest slope thermal weathering leaves alone, as the height difference per cell.
+    #[var]
+    thermal_talus: f32,
+    /// The fraction of the material over the talus moved by each thermal pass.
+    #[var]
+    thermal_rate: f32,
+    /// Run a thermal pass after every this many iterations - `0` turns them off.
+    #[var]
+    thermal_interval: u32,
+    /// How many shallow-water time steps make up a single iteration.
+    #[var]
+    water_steps: u32,
+    /// The length of a single shallow-water time step.
+    #[var]
+    water_time_step: f32,
+    /// How much water rains onto every cell per unit of time.
+    #[var]
+    water_rain_rate: f32,
+    /// How strongly differences in water height push water between cells.
+    #[var]
+    water_gravity: f32,
+    /// How much sediment the water can carry for its speed and slope.
+    #[var]
+    water_sediment_capacity: f32,
+    /// How quickly the water dissolves terrain when it can carry more.
+    #[var]
+    water_dissolving: f32,
+    /// How quickly the water deposits sediment it can't carry.
+    #[var]
+    water_deposition: f32,
+    /// The fraction of the water that evaporates per unit of time.
+    #[var]
+    water_evaporation: f32,
+    /// The smallest tilt used for the carrying capacity, so flat ground still erodes.
+    #[var]
+    water_min_tilt: f32,
+    /// Water deeper than this carries no more sediment than water this deep.
+    #[var]
+    water_max_erosion_depth: f32,
+    /// Use the green channel of the terrain texture as the rock hardness map,
+    /// from `0.0` (soft) to `1.0` (can't be eroded).
+    #[var]
+    hardness_from_green: bool,
+    /// How quickly rock hardens as the material above it is eroded away, and softens
+    /// under fresh sediment - `0.0` keeps the hardness map as it was loaded.
+    #[var]
+    hardening: f32,
+    /// How much more rain the highest point gets than the lowest - `1.0` doubles it.
+    #[var]
+    rain_elevation: f32,
+    /// How much more rain the steepest slope facing into the wind gets - `1.0` doubles it.
+    #[var]
+    rain_windward: f32,
+    /// The direction the wind blows towards, in degrees clockwise from the `+x` axis.
+    #[var]
+    rain_wind_direction: f32,
+    /// The fraction of the flow map kept from one iteration to the next - the rest fades
+    /// away so it follows where the water runs now.
+    #[var]
+    flow_memory: f32,
+    /// The kind of noise `generate_terrain` makes terrain from - `"fbm"` or `"ridged"`.
+    #[var]
+    terrain_noise: GString,
+    /// The size of the biggest features `generate_terrain` makes, in cells.
+    #[var]
+    terrain_scale: f32,
+    /// How many layers of ever finer noise `generate_terrain` adds together.
+    #[var]
+    terrain_octaves: u32,
+    /// How much finer each octave of generated terrain is than the one before.
+    #[var]
+    terrain_lacunarity: f32,
+    /// How much weaker each octave of generated terrain is than the one before.
+    #[var]
+    terrain_persistence: f32,
+    /// How far, in cells, generated terrain is pushed around by domain warping - `0.0` turns
+    /// it off.
+    #[var]
+    terrain_warp: f32,
+    /// Seed for `generate_terrain` - the same seed and parameters always give the same terrain.
+    #[var]
+    terrain_seed: i64,
+    /// How much to exaggerate the slopes in `normal_texture`.
+    #[var]
+    normal_strength: f32,
+    /// Bake `normal_texture`, `slope_texture` and `curvature_texture` again every iteration -
+    /// otherwise they're only baked when the simulation stops, which saves time on big maps.
+    #[var]
+    bake_surface_maps: bool,
+    /// A tangent-space normal map of the terrain.
+    #[var(get)]
+    normal_texture: Option<Gd<ImageTexture>>,
+    /// How steep every cell of the terrain is, as the height difference per cell.
+    #[var(get)]
+    slope_texture: Option<Gd<ImageTexture>>,
+    /// How convex every cell of the terrain is - positive on ridges, negative in valleys.
+    #[var(get)]
+    curvature_texture: Option<Gd<ImageTexture>>,
+    /// Seed for everything random in the simulation - the same seed, texture and
+    /// parameters always erode to the same result.
+    #[var]
+    seed: i64,
+    /// How many megabytes the undo history can take up - the oldest iterations are forgotten
+    /// past that. While the simulation runs, one more copy of the terrain, hardness and
+    /// layers is kept to work out what each iteration changed.
+    #[var]
+    history_megabytes: u32,
+    // The material layers - these can't be edited in the inspector, so they come from
+    // `load_params`
+    layers: Vec<LayerParams>,
 }
 
 #[godot_api]
 impl IMeshInstance3D for TerrainMesh {
     fn init(base: Base<MeshInstance3D>) -> Self {
         godot_print!("Hello, world!"); // Prints to the Godot console
+        let params = ErosionParams::default();
+        let terrain = TerrainParams::default();
         Self {
             base,
-            gravity: 10.0,
-            capacity: 2.0,
-            inertia: 0.3,
-            erosion_factor: 0.3,
-            deposition_factor: 0.3,
-            diameter: 3.0,
-            lifetime: 50,
-            starting_mass: 1.0,
+            input_path: "res://terrain_texture.exr".into(),
+            output_path: "output.exr".into(),
+            input_height_scale: 1.0,
+            normalize_input: false,
+            input_range: Vector2::new(0.0, 1.0),
+            model: params.model.to_string().into(),
+            gravity: params.gravity,
+            capacity: params.capacity,
+            inertia: params.inertia,
+            erosion_factor: params.erosion_factor,
+            deposition_factor: params.deposition_factor,
+            diameter: params.diameter,
+            lifetime: params.lifetime,
+            starting_mass: params.starting_mass,
+            evaporation: params.evaporation,
+            min_velocity: params.min_velocity,
+            boundary: params.boundary.to_string().into(),
+            drop_count: params.drop_count as u32,
+            tiled: params.tiled,
+            pyramid_levels: params.pyramid_levels,
+            pyramid_iterations: params.pyramid_iterations,
+            thermal_talus: params.thermal_talus,
+            thermal_rate: params.thermal_rate,
+            thermal_interval: params.thermal_interval,
+            water_steps: params.shallow_water.steps,
+            water_time_step: params.shallow_water.time_step,
+            water_rain_rate: params.shallow_water.rain_rate,
+            water_gravity: params.shallow_water.gravity,
+            water_sediment_capacity: params.shallow_water.sediment_capacity,
+            water_dissolving: params.shallow_water.dissolving,
+            water_deposition: params.shallow_water.deposition,
+            water_evaporation: params.shallow_water.evaporation,
+            water_min_tilt: params.shallow_water.min_tilt,
+            water_max_erosion_depth: params.shallow_water.max_erosion_depth,
+            hardness_from_green: false,
+            hardening: params.hardening,
+            rain_elevation: params.rain_elevation,
+            rain_windward: params.rain_windward,
+            rain_wind_direction: params.rain_wind_direction,
+            flow_memory: params.flow_memory,
+            terrain_noise: terrain.noise.to_string().into(),
+            terrain_scale: terrain.scale,
+            terrain_octaves: terrain.octaves,
+            terrain_lacunarity: terrain.lacunarity,
+            terrain_persistence: terrain.persistence,
+            terrain_warp: terrain.warp,
+            terrain_seed: terrain.seed as i64,
+            normal_strength: 1.0,
+            bake_surface_maps: true,
+            normal_texture: None,
+            slope_texture: None,
+            curvature_texture: None,
+            seed: 0,
+            history_megabytes: 256,
+            layers: params.layers,
         }
     }
 
     fn ready(&mut self) {
-        // Get base terrain texture resource
-        let resource = ResourceLoader::singleton()
-            .load("res://terrain_texture.exr")
-            .expect("terrain_texture.exr not found");
-
-        // Try to cast the resource to a CompressedTexture2D
-        match resource.try_cast::<CompressedTexture2D>() {
-            Ok(base_texture) => {
-                // Get the dimensions of the texture
-                let image = base_texture.get_image().unwrap();
-                let x = image.get_width();
-                let y = image.get_height();
-
-                // Set the dimensions of the texture
-                *DIMS.write().unwrap() = (x as usize, y as usize);
-
-                godot_print!("Texture dimensions: ({}, {})", x, y);
-
-                // Get data for the texture
-                let data = image.get_data().to_vec();
-
-                godot_print!("{:?}", data.len());
-
-                let mut converted: Vec<f32> = data
-                    .chunks_exact(4)
-                    .map(TryInto::try_into)
-                    .map(Result::unwrap)
-                    .map(f32::from_le_bytes)
-                    .collect();
-
-                godot_print!("{:?}", converted.len());
-
-                if data.len() < (x * y * 4).try_into().unwrap() {
-                    godot_error!("Data length is less than expected");
-                    return;
-                }
-
-                let image_format = base_texture.get_image().unwrap().get_format();
-
-                let bytes_to_skip = match image_format {
-                    Format::RGF => 2,
-                    Format::RGBF => 3,
-                    Format::RGBAF => 4,
-                    _ => {
-                        godot_error!("Unsupported image format: {:?}", image_format);
-                        return;
-                    }
-                };
-
-                // Grab only every 3rd element
-                converted = converted
-                    .iter()
-                    .enumerate()
-                    .filter(|(i, _)| i % bytes_to_skip == 0)
-                    .map(|(_, x)| *x)
-                    .collect();
-
-                // Put the data into the static texture
-                let mut texture_lock = TEXTURE.write().unwrap();
-                texture_lock.clear();
-                texture_lock.extend(converted.clone());
-                godot_print!("{:?}", texture_lock.len());
-
-                // Create BytePackedArray
-                let mut array = PackedByteArray::new();
-                array.extend(
-                    converted
-                        .iter()
-                        .flat_map(|&x| x.to_le_bytes())
-                        .collect::<Vec<u8>>(),
-                );
-
-                // Create a new texture
-                let new_image = Image::create_from_data(x, y, false, Format::RF, &array).unwrap();
-
-                let new_texture = ImageTexture::create_from_image(&new_image).unwrap();
-
-                *IMAGE_ID.write().unwrap() = new_texture.get_rid();
-
-                // Create a new ShaderMaterial
-                let mut material = ShaderMaterial::new_gd();
-                material.set_shader_parameter("terrain_texture", &new_texture.to_variant());
-
-                // Load the height shader
-                let shader_resource = ResourceLoader::singleton()
-                    .load("res://height_shader.gdshader")
-                    .expect("height_shader.gdshader not found");
-
-                // Try to cast the resource to a Shader
-                let shader = shader_resource.cast::<Shader>();
-                material.set_shader(&shader);
-
-                // Set the ShaderMaterial on the mesh
-                self.base_mut().set_surface_override_material(0, &material);
-            }
-            Err(e) => godot_error!("Failed to cast resource to Material: {:?}", e),
-        }
+        let path = self.input_path.clone();
+        self.load_heightmap(path);
     }
 
     fn process(&mut self, delta: f64) {
@@ -208,15 +345,19 @@ impl TerrainMesh {
             return;
         }
 
-        // Get all data for the thread
-        let gravity = self.gravity;
-        let capacity = self.capacity;
-        let inertia = self.inertia;
-        let erosion_factor = self.erosion_factor;
-        let deposition_factor = self.deposition_factor;
-        let diameter = self.diameter;
-        let lifetime = self.lifetime;
-        let starting_mass = self.starting_mass;
+        let mut simulator = self.simulator();
+        if let Err(e) = simulator.params.check_dims(simulator.dims) {
+            godot_error!("Can't start the simulation: {e}");
+            return;
+        }
+        HISTORY
+            .lock()
+            .unwrap()
+            .set_capacity(self.history_capacity());
+
+        let output = globalize_path(&self.output_path);
+        let (strength, wrap) = (self.normal_strength, simulator.params.wraps());
+        let bake_surface_maps = self.bake_surface_maps;
 
         let (sender, reciever) = channel::<()>();
 
@@ -227,15 +368,23 @@ impl TerrainMesh {
                 let mut vs: Gd<RenderingServer> = RenderingServer::singleton();
 
                 // Get the texture's dimensions
-                let dims = *DIMS.read().unwrap();
+                let dims = simulator.dims;
 
-                // Start a counter for the number of iterations
-                let mut counter: usize = 0;
+                // The maps as they were before the last iteration, kept up to date in place
+                // to record what each one changed
+                simulator.update_strata_layers();
+                let mut snapshot = history_maps(&simulator);
 
-                // Get the texture data and clone it into a new Arc for async purposes
-                let texture = TEXTURE.read().unwrap().clone();
-                let texture_rwlock = RwLock::new(texture);
-                let texture_arc = Arc::new(texture_rwlock);
+                // Carve out the big valleys on coarser copies of the terrain first, unless
+                // carrying on from an earlier run
+                if simulator.params.pyramid_levels > 0 && simulator.iteration() == 0 {
+                    let start = SystemTime::now();
+                    let changes = simulator.run_pyramid();
+                    let duration = SystemTime::now().duration_since(start).unwrap();
+                    godot_print!("Pyramid levels took: {duration:?}, made {changes} changes");
+
+                    record_pass(&mut snapshot, &simulator);
+                }
 
                 godot_print!("Starting physics loop");
                 loop {
@@ -248,75 +397,99 @@ impl TerrainMesh {
                         break;
                     }
 
-                    // Create Raindrops
-                    let mut drops: Vec<Raindrop> =
-                        create_raindrops(20_000, starting_mass, *DIMS.read().unwrap());
-
-                    // Simulate Raindrops
-                    // Using the map function - add/remove the `par_` to add/remove parallelism
-                    let changes: Vec<(f32, usize)> = drops
-                        .par_iter_mut()
-                        .map(|drop| {
-                            drop.simulate(
-                                Arc::clone(&texture_arc),
-                                *DIMS.read().unwrap(),
-                                gravity,
-                                capacity,
-                                inertia,
-                                erosion_factor,
-                                deposition_factor,
-                                diameter,
-                                lifetime,
-                            )
-                        })
-                        .flatten()
-                        .collect();
-
-                    // Get mutable access to the texture
-                    let mut texture = texture_arc.write().unwrap();
-
-                    // Update the texture with the changes
-                    for change in changes.iter() {
-                        texture[change.1] += change.0;
-                    }
+                    // Run a single iteration of the simulation
+                    let changes = simulator.step();
+
+                    // Remember what it changed, so it can be undone
+                    record_pass(&mut snapshot, &simulator);
 
-                    // Update the texture in Godot
+                    // Update the textures in Godot
+                    let size = (dims.0 as i32, dims.1 as i32);
                     update_texture(
-                        &texture,
-                        (dims.0 as i32, dims.1 as i32),
+                        &simulator.heightmap,
+                        size,
                         *IMAGE_ID.read().unwrap(),
                         &mut vs,
                     );
-                    counter += 1;
+                    update_texture(
+                        &simulator.erosion,
+                        size,
+                        *EROSION_ID.read().unwrap(),
+                        &mut vs,
+                    );
+                    update_texture(
+                        &simulator.deposition,
+                        size,
+                        *DEPOSITION_ID.read().unwrap(),
+                        &mut vs,
+                    );
+                    update_texture(&simulator.flow, size, *FLOW_ID.read().unwrap(), &mut vs);
+
+                    // Bake the shape of the terrain again now it's changed, if asked to
+                    if bake_surface_maps {
+                        update_surface_maps(&simulator.heightmap, dims, strength, wrap, &mut vs);
+                    }
 
                     // Get the end time for iteration speed testing
                     let end = SystemTime::now();
                     let duration = end.duration_since(start).unwrap();
                     godot_print!(
-                        "Iteration {counter} took: {duration:?}, made {} changes",
-                        changes.len()
+                        "Iteration {} took: {duration:?}, made {changes} changes",
+                        simulator.iteration()
                     );
                 }
 
-                // Update the texture in global state
-                let texture_lock = texture_arc.read().unwrap().clone();
+                // Otherwise the shape of the terrain is only baked once it's stopped
+                if !bake_surface_maps {
+                    update_surface_maps(&simulator.heightmap, dims, strength, wrap, &mut vs);
+                }
 
-                // Import here, otherwise we get weird errors :|
-                // I think this is due to exr having traits that effect Vectors.
-                use exr::prelude::*;
+                // Keep the iteration count, RNG and water to carry on from next time
+                *PROGRESS.lock().unwrap() = Some(simulator.progress());
 
-                // Output the image
-                write_rgb_file("output.exr", dims.0, dims.1, |x, y| {
-                    let index = y * dims.0 + x;
-                    let r = texture_lock[index];
+                // Keep the hardness too, in case it was worn down
+                *HARDNESS.write().unwrap() = simulator.hardness().map(<[f32]>::to_vec);
 
-                    (r, r, r)
-                })
-                .unwrap();
+                // Output the material layers next to the image, and keep them for next time
+                if let Some(strata) = simulator.strata() {
+                    let path = sibling_path(&output, "layers");
+                    if let Err(e) = io::write_strata(&path, strata, dims) {
+                        godot_error!("Failed to write {path}: {e}");
+                    }
+                }
+                *STRATA.write().unwrap() = simulator.strata().cloned();
+
+                // Output where material was removed and laid down, and keep adding to it next time
+                let maps = [
+                    ("erosion", simulator.erosion.as_slice()),
+                    ("deposition", simulator.deposition.as_slice()),
+                ];
+                let path = sibling_path(&output, "sediment");
+                if let Err(e) = io::write_exr_channels(&path, &maps, dims) {
+                    godot_error!("Failed to write {path}: {e}");
+                }
+                *EROSION.write().unwrap() = std::mem::take(&mut simulator.erosion);
+                *DEPOSITION.write().unwrap() = std::mem::take(&mut simulator.deposition);
+
+                // Output where the water has been flowing, and carry on from it next time
+                let maps = [("flow", simulator.flow.as_slice())];
+                let path = sibling_path(&output, "flow");
+                if let Err(e) = io::write_exr_channels(&path, &maps, dims) {
+                    godot_error!("Failed to write {path}: {e}");
+                }
+                *FLOW.write().unwrap() = std::mem::take(&mut simulator.flow);
+
+                // Take the eroded texture out of the simulator
+                let texture = simulator.heightmap;
+
+                // Output the image
+                if let Err(e) = io::write_heightmap(&output, &texture, dims) {
+                    godot_error!("Failed to write {output}: {e}");
+                }
 
                 // Save the texture to the global state
                 let mut global_texture_lock = TEXTURE.write().unwrap();
-                *global_texture_lock = texture_lock;
+                *global_texture_lock = texture;
             }),
             sender,
         ));
@@ -330,6 +503,905 @@ impl TerrainMesh {
             thread.join().unwrap();
         }
     }
+
+    #[func]
+    /// Undoes the last iteration of erosion, returning whether there was one to undo. The terrain
+    /// goes back along with its hardness and material layers, and any shallow water starts
+    /// again - the sediment and flow maps and the iteration count don't go back.
+    fn undo(&mut self) -> bool {
+        self.step_history(History::undo, "undo")
+    }
+
+    #[func]
+    /// Redoes the last iteration undone, returning whether there was one to redo. Running the
+    /// simulation again forgets everything that could be redone.
+    fn redo(&mut self) -> bool {
+        self.step_history(History::redo, "redo")
+    }
+
+    #[func]
+    /// How many iterations can be undone.
+    fn get_undo_count() -> u32 {
+        HISTORY.lock().unwrap().undo_len() as u32
+    }
+
+    #[func]
+    /// How many iterations can be redone.
+    fn get_redo_count() -> u32 {
+        HISTORY.lock().unwrap().redo_len() as u32
+    }
+
+    #[func]
+    /// Forgets every iteration that could be undone or redone, freeing the memory.
+    fn clear_history() {
+        HISTORY.lock().unwrap().clear();
+    }
+
+    #[func]
+    /// Loads a heightmap to erode from a `res://`, `user://` or absolute path, returning whether
+    /// it worked. EXR, 16-bit PNG, `.r16`/`.raw` and ESRI ASCII grid files are supported.
+    ///
+    /// The sediment and flow maps start again from nothing and the material layers from the
+    /// parameters, and hardness and precipitation maps that no longer fit are removed.
+    fn load_heightmap(&mut self, path: GString) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before loading a heightmap");
+            return false;
+        }
+
+        let Some(LoadedHeightmap {
+            heightmap,
+            hardness,
+            dims,
+        }) = read_heightmap(&path, self.hardness_from_green, self.height_scale())
+        else {
+            return false;
+        };
+
+        godot_print!("Loaded {path} with dimensions {dims:?}");
+        self.replace_heightmap(heightmap, hardness, dims);
+
+        true
+    }
+
+    #[func]
+    /// Generates a new base terrain `width` by `height` cells from fractal noise set up by the
+    /// `terrain_` parameters, returning whether it worked. It replaces the current terrain just
+    /// like `load_heightmap`.
+    fn generate_terrain(&mut self, width: u32, height: u32) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before generating a terrain");
+            return false;
+        }
+        if width < 2 || height < 2 {
+            godot_error!("A terrain has to be at least 2x2, not {width}x{height}");
+            return false;
+        }
+
+        let noise = match self.terrain_noise.to_string().parse() {
+            Ok(noise) => noise,
+            Err(e) => {
+                godot_error!("{e}");
+                return false;
+            }
+        };
+        let params = TerrainParams {
+            noise,
+            seed: self.terrain_seed as u64,
+            scale: self.terrain_scale,
+            octaves: self.terrain_octaves,
+            lacunarity: self.terrain_lacunarity,
+            persistence: self.terrain_persistence,
+            warp: self.terrain_warp,
+        };
+
+        let dims = (width as usize, height as usize);
+        let start = SystemTime::now();
+        let heightmap = generate(dims, &params);
+        let duration = SystemTime::now().duration_since(start).unwrap();
+        godot_print!("Generated a {width}x{height} terrain in {duration:?}");
+
+        self.replace_heightmap(heightmap, None, dims);
+
+        true
+    }
+
+    #[func]
+    /// Saves everything needed to carry on from where the simulation last stopped - the
+    /// terrain, every map, the parameters, the RNG and the iteration count - to a checkpoint
+    /// file, returning whether it worked.
+    fn save_checkpoint(&self, path: GString) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before saving a checkpoint");
+            return false;
+        }
+
+        match self.simulator().save_checkpoint(globalize_path(&path)) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to save a checkpoint to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Loads a checkpoint saved by `save_checkpoint`, returning whether it worked. The
+    /// parameters and seed are set from it, and the simulation carries on from exactly where
+    /// it was when it's started again.
+    fn load_checkpoint(&mut self, path: GString) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before loading a checkpoint");
+            return false;
+        }
+
+        let simulator = match ErosionSimulator::load_checkpoint(globalize_path(&path)) {
+            Ok(simulator) => simulator,
+            Err(e) => {
+                godot_error!("Failed to load a checkpoint from {path}: {e}");
+                return false;
+            }
+        };
+
+        let dims = simulator.dims;
+        godot_print!(
+            "Loaded a checkpoint at iteration {} with dimensions {dims:?}",
+            simulator.iteration()
+        );
+
+        self.set_params(&simulator.params);
+        self.seed = simulator.seed() as i64;
+        self.replace_heightmap(simulator.heightmap.clone(), None, dims);
+
+        *HARDNESS.write().unwrap() = simulator.hardness().map(<[f32]>::to_vec);
+        *PRECIPITATION.write().unwrap() = simulator.precipitation().map(<[f32]>::to_vec);
+        *STRATA.write().unwrap() = simulator.strata().cloned();
+        *PROGRESS.lock().unwrap() = Some(simulator.progress());
+
+        // Show the sediment and flow maps it got to, rather than empty ones
+        let size = (dims.0 as i32, dims.1 as i32);
+        let mut rs = RenderingServer::singleton();
+        update_texture(
+            &simulator.erosion,
+            size,
+            *EROSION_ID.read().unwrap(),
+            &mut rs,
+        );
+        update_texture(
+            &simulator.deposition,
+            size,
+            *DEPOSITION_ID.read().unwrap(),
+            &mut rs,
+        );
+        update_texture(&simulator.flow, size, *FLOW_ID.read().unwrap(), &mut rs);
+        *EROSION.write().unwrap() = simulator.erosion;
+        *DEPOSITION.write().unwrap() = simulator.deposition;
+        *FLOW.write().unwrap() = simulator.flow;
+
+        true
+    }
+
+    #[func]
+    /// Starts the iteration count, RNG and shallow water over the next time the simulation
+    /// starts, rather than carrying on from where it stopped.
+    fn reset_progress() {
+        *PROGRESS.lock().unwrap() = None;
+    }
+
+    #[func]
+    /// Saves the terrain as it was the last time the simulation stopped to a `res://`, `user://`
+    /// or absolute path, returning whether it worked - as raw floats for `.f32` files and as an
+    /// EXR otherwise.
+    fn save_heightmap(&self, path: GString) -> bool {
+        let dims = *DIMS.read().unwrap();
+
+        match io::write_heightmap(globalize_path(&path), &TEXTURE.read().unwrap(), dims) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to save the heightmap to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Exports the terrain as it was the last time the simulation stopped as a triangle mesh
+    /// with normals and UVs, to an `.obj`, `.gltf` or `.glb` file - returning whether it worked.
+    /// `spacing` is the horizontal distance between cells, every height is multiplied by
+    /// `height_scale`, and only every `step` cells gets a vertex.
+    fn export_mesh(&self, path: GString, spacing: f32, height_scale: f32, step: u32) -> bool {
+        let options = MeshOptions {
+            spacing,
+            height_scale,
+            step: step as usize,
+        };
+        let mesh =
+            TriangleMesh::from_heightmap(&TEXTURE.read().unwrap(), *DIMS.read().unwrap(), &options);
+
+        match mesh.and_then(|mesh| mesh.save(globalize_path(&path))) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to export the mesh to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Saves a tangent-space normal map of the terrain as it was the last time the simulation
+    /// stopped, returning whether it worked - as a 16-bit PNG for `.png` files and as an EXR
+    /// otherwise.
+    fn save_normal_map(&self, path: GString) -> bool {
+        let dims = *DIMS.read().unwrap();
+        let maps = self.surface_maps();
+
+        match io::write_normal_map(globalize_path(&path), &maps.normal_colours(), dims) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to save the normal map to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Saves how steep and how convex the terrain was the last time the simulation stopped to
+    /// the `slope` and `curvature` channels of an EXR, returning whether it worked.
+    fn save_surface_maps(&self, path: GString) -> bool {
+        let dims = *DIMS.read().unwrap();
+        let maps = self.surface_maps();
+        let channels = [
+            ("slope", maps.slope.as_slice()),
+            ("curvature", maps.curvature.as_slice()),
+        ];
+
+        match io::write_exr_channels(globalize_path(&path), &channels, dims) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to save the surface maps to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Loads a rock hardness map from an image the same size as the terrain, returning whether
+    /// it worked. The red channel is used, from `0.0` (soft) to `1.0` (can't be eroded).
+    ///
+    /// The undo history was recorded against the old hardness, so it starts over.
+    fn load_hardness(&mut self, path: GString) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before loading a hardness map");
+            return false;
+        }
+
+        let Some(hardness) = load_map(&path, "hardness map") else {
+            return false;
+        };
+
+        *HARDNESS.write().unwrap() = Some(hardness);
+        HISTORY.lock().unwrap().clear();
+        true
+    }
+
+    #[func]
+    /// Removes the rock hardness map, making all of the terrain equally soft, and returns
+    /// whether it did. The undo history was recorded against the old hardness, so it starts
+    /// over.
+    fn clear_hardness() -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before clearing the hardness map");
+            return false;
+        }
+
+        *HARDNESS.write().unwrap() = None;
+        HISTORY.lock().unwrap().clear();
+        true
+    }
+
+    #[func]
+    /// Loads a precipitation map from an image the same size as the terrain, returning whether
+    /// it worked. The red channel is used, and cells get rain in proportion to it.
+    fn load_precipitation(&mut self, path: GString) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before loading a precipitation map");
+            return false;
+        }
+
+        let Some(precipitation) = load_map(&path, "precipitation map") else {
+            return false;
+        };
+
+        *PRECIPITATION.write().unwrap() = Some(precipitation);
+        true
+    }
+
+    #[func]
+    /// Removes the precipitation map, so rain falls evenly apart from the `rain_` parameters,
+    /// and returns whether it did.
+    fn clear_precipitation() -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before clearing the precipitation map");
+            return false;
+        }
+
+        *PRECIPITATION.write().unwrap() = None;
+        true
+    }
+
+    #[func]
+    /// Clears the erosion and deposition maps, so they only show what happens from now on.
+    fn reset_sediment_maps() {
+        let dims = *DIMS.read().unwrap();
+        let size = (dims.0 as i32, dims.1 as i32);
+        let empty = vec![0.0; dims.0 * dims.1];
+
+        let mut rs = RenderingServer::singleton();
+        update_texture(&empty, size, *EROSION_ID.read().unwrap(), &mut rs);
+        update_texture(&empty, size, *DEPOSITION_ID.read().unwrap(), &mut rs);
+
+        *EROSION.write().unwrap() = empty.clone();
+        *DEPOSITION.write().unwrap() = empty;
+    }
+
+    #[func]
+    /// Throws away the eroded material layers, so the next run starts them again from the
+    /// layers in the parameters. The undo history was recorded against the old layers, so it
+    /// starts over.
+    fn reset_layers() {
+        *STRATA.write().unwrap() = None;
+        HISTORY.lock().unwrap().clear();
+    }
+
+    #[func]
+    /// Loads the simulation parameters from a `.toml` or `.json` file, returning whether it worked.
+    fn load_params(&mut self, path: GString) -> bool {
+        match ErosionParams::load(globalize_path(&path)) {
+            Ok(params) => {
+                self.set_params(&params);
+                true
+            }
+            Err(e) => {
+                godot_error!("Failed to load parameters from {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Saves the simulation parameters to a `.toml` or `.json` file, returning whether it worked.
+    fn save_params(&self, path: GString) -> bool {
+        match self.params().save(globalize_path(&path)) {
+            Ok(()) => true,
+            Err(e) => {
+                godot_error!("Failed to save parameters to {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Applies one of the built-in parameter presets, returning whether it exists.
+    fn apply_preset(&mut self, name: GString) -> bool {
+        match ErosionParams::preset(&name.to_string()) {
+            Some(params) => {
+                self.set_params(&params);
+                true
+            }
+            None => {
+                godot_error!("Unknown preset: {name}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    /// Lists the names of the built-in parameter presets.
+    fn get_presets() -> PackedStringArray {
+        PRESETS.iter().map(|&name| GString::from(name)).collect()
+    }
+}
+
+impl TerrainMesh {
+    /// Makes `heightmap` the terrain to erode, with `hardness` as its rock hardness if there is
+    /// one. The sediment and flow maps start again from nothing, the material layers from the
+    /// parameters and the iteration count from zero, the undo history is forgotten, and
+    /// hardness and precipitation maps that no longer fit are removed.
+    fn replace_heightmap(
+        &mut self,
+        heightmap: Vec<f32>,
+        hardness: Option<Vec<f32>>,
+        dims: (usize, usize),
+    ) {
+        // Maps made for a terrain of a different size don't fit this one
+        if *DIMS.read().unwrap() != dims {
+            *HARDNESS.write().unwrap() = None;
+            *PRECIPITATION.write().unwrap() = None;
+        }
+        if hardness.is_some() {
+            *HARDNESS.write().unwrap() = hardness;
+        }
+        *STRATA.write().unwrap() = None;
+        *PROGRESS.lock().unwrap() = None;
+        HISTORY.lock().unwrap().clear();
+
+        *DIMS.write().unwrap() = dims;
+        self.show_heightmap(&heightmap, dims);
+        *TEXTURE.write().unwrap() = heightmap;
+    }
+
+    /// Shows `heightmap` on the mesh, with new textures for it and for empty erosion,
+    /// deposition and flow maps.
+    fn show_heightmap(&mut self, heightmap: &[f32], dims: (usize, usize)) {
+        let size = (dims.0 as i32, dims.1 as i32);
+        let texture = float_texture(heightmap, size);
+        *IMAGE_ID.write().unwrap() = texture.get_rid();
+
+        // Start the erosion and deposition maps out empty, with textures of their own
+        let empty = vec![0.0; heightmap.len()];
+        let erosion_texture = float_texture(&empty, size);
+        let deposition_texture = float_texture(&empty, size);
+        let flow_texture = float_texture(&empty, size);
+        *EROSION_ID.write().unwrap() = erosion_texture.get_rid();
+        *DEPOSITION_ID.write().unwrap() = deposition_texture.get_rid();
+        *FLOW_ID.write().unwrap() = flow_texture.get_rid();
+        *EROSION.write().unwrap() = empty.clone();
+        *DEPOSITION.write().unwrap() = empty.clone();
+        *FLOW.write().unwrap() = empty;
+
+        // Bake the shape of the terrain into textures of its own
+        let maps = SurfaceMaps::bake(heightmap, dims, self.normal_strength, self.params().wraps());
+        let normal_texture =
+            ImageTexture::create_from_image(&normal_image(&maps.normal_colours(), size)).unwrap();
+        let slope_texture = float_texture(&maps.slope, size);
+        let curvature_texture = float_texture(&maps.curvature, size);
+        *NORMAL_ID.write().unwrap() = normal_texture.get_rid();
+        *SLOPE_ID.write().unwrap() = slope_texture.get_rid();
+        *CURVATURE_ID.write().unwrap() = curvature_texture.get_rid();
+
+        // Create a new ShaderMaterial
+        let mut material = ShaderMaterial::new_gd();
+        material.set_shader_parameter("terrain_texture", &texture.to_variant());
+        material.set_shader_parameter("erosion_texture", &erosion_texture.to_variant());
+        material.set_shader_parameter("deposition_texture", &deposition_texture.to_variant());
+        material.set_shader_parameter("flow_texture", &flow_texture.to_variant());
+        material.set_shader_parameter("normal_texture", &normal_texture.to_variant());
+        material.set_shader_parameter("slope_texture", &slope_texture.to_variant());
+        material.set_shader_parameter("curvature_texture", &curvature_texture.to_variant());
+        self.normal_texture = Some(normal_texture);
+        self.slope_texture = Some(slope_texture);
+        self.curvature_texture = Some(curvature_texture);
+
+        // Load the height shader
+        let shader_resource = ResourceLoader::singleton()
+            .load("res://height_shader.gdshader")
+            .expect("height_shader.gdshader not found");
+
+        // Try to cast the resource to a Shader
+        let shader = shader_resource.cast::<Shader>();
+        material.set_shader(&shader);
+
+        // Set the ShaderMaterial on the mesh
+        self.base_mut().set_surface_override_material(0, &material);
+    }
+
+    /// Sets up a simulation of the current terrain and maps, carrying on from where the last
+    /// one stopped.
+    ///
+    /// The RNG starts again from `seed` if that's been changed since.
+    fn simulator(&self) -> ErosionSimulator {
+        let mut simulator =
+            ErosionSimulator::new(TEXTURE.read().unwrap().clone(), *DIMS.read().unwrap());
+        simulator.params = self.params();
+        simulator.set_hardness(HARDNESS.read().unwrap().clone());
+        simulator.set_strata(STRATA.read().unwrap().clone());
+        simulator.set_precipitation(PRECIPITATION.read().unwrap().clone());
+        simulator.erosion.clone_from(&EROSION.read().unwrap());
+        simulator.deposition.clone_from(&DEPOSITION.read().unwrap());
+        simulator.flow.clone_from(&FLOW.read().unwrap());
+        simulator.reseed(self.seed as u64);
+
+        if let Some(progress) = PROGRESS.lock().unwrap().clone() {
+            let reseeded = progress.seed != self.seed as u64;
+            simulator.resume(progress);
+            if reseeded {
+                simulator.reseed(self.seed as u64);
+            }
+        }
+
+        simulator
+    }
+
+    /// Moves the terrain through the undo history with `step` (`History::undo` or
+    /// `History::redo`), then shows it - returning whether it moved.
+    fn step_history(
+        &mut self,
+        step: fn(&mut History, &mut [&mut [f32]]) -> bool,
+        action: &str,
+    ) -> bool {
+        if THREAD.lock().unwrap().is_some() {
+            godot_error!("Stop the simulation before trying to {action}");
+            return false;
+        }
+
+        let mut history = HISTORY.lock().unwrap();
+        history.set_capacity(self.history_capacity());
+
+        // The terrain, and the hardness and layers that wore down with it
+        let mut texture = TEXTURE.write().unwrap();
+        let mut hardness = HARDNESS.write().unwrap();
+        let mut strata = STRATA.write().unwrap();
+        let mut maps = [
+            texture.as_mut_slice(),
+            hardness.as_deref_mut().unwrap_or_default(),
+            strata
+                .as_mut()
+                .map(Strata::packed_thickness_mut)
+                .unwrap_or_default(),
+        ];
+        if !step(&mut history, &mut maps) {
+            return false;
+        }
+        drop((history, texture, hardness, strata));
+
+        // The water was flowing over a different terrain, so it starts again
+        if let Some(progress) = PROGRESS.lock().unwrap().as_mut() {
+            progress.shallow_water = None;
+        }
+
+        // Show the terrain as it is now, and bake its shape again
+        let dims = *DIMS.read().unwrap();
+        let size = (dims.0 as i32, dims.1 as i32);
+        let texture = TEXTURE.read().unwrap();
+        let mut rs = RenderingServer::singleton();
+        update_texture(&texture, size, *IMAGE_ID.read().unwrap(), &mut rs);
+        let wrap = self.params().wraps();
+        update_surface_maps(&texture, dims, self.normal_strength, wrap, &mut rs);
+
+        true
+    }
+
+    /// How `load_heightmap` rescales the heights it reads.
+    fn height_scale(&self) -> io::HeightScale {
+        if self.normalize_input {
+            io::HeightScale::Normalize {
+                min: self.input_range.x,
+                max: self.input_range.y,
+            }
+        } else {
+            io::HeightScale::Scale(self.input_height_scale)
+        }
+    }
+
+    /// How many bytes the undo history can take up.
+    fn history_capacity(&self) -> usize {
+        self.history_megabytes as usize * 1024 * 1024
+    }
+
+    /// Bakes the normal, slope and curvature maps of the terrain as it was the last time the
+    /// simulation stopped.
+    fn surface_maps(&self) -> SurfaceMaps {
+        SurfaceMaps::bake(
+            &TEXTURE.read().unwrap(),
+            *DIMS.read().unwrap(),
+            self.normal_strength,
+            self.params().wraps(),
+        )
+    }
+
+    /// Gathers the exported parameters into an `ErosionParams`.
+    fn params(&self) -> ErosionParams {
+        let model = self.model.to_string().parse().unwrap_or_else(|e| {
+            godot_error!("{e}, using the droplet model");
+            ErosionModel::Droplet
+        });
+        let boundary = self.boundary.to_string().parse().unwrap_or_else(|e| {
+            godot_error!("{e}, depositing at the edges");
+            Boundary::Deposit
+        });
+
+        ErosionParams {
+            model,
+            gravity: self.gravity,
+            capacity: self.capacity,
+            inertia: self.inertia,
+            erosion_factor: self.erosion_factor,
+            deposition_factor: self.deposition_factor,
+            diameter: self.diameter,
+            lifetime: self.lifetime,
+            starting_mass: self.starting_mass,
+            evaporation: self.evaporation,
+            min_velocity: self.min_velocity,
+            boundary,
+            drop_count: self.drop_count as usize,
+            tiled: self.tiled,
+            pyramid_levels: self.pyramid_levels,
+            pyramid_iterations: self.pyramid_iterations,
+            thermal_talus: self.thermal_talus,
+            thermal_rate: self.thermal_rate,
+            thermal_interval: self.thermal_interval,
+            hardening: self.hardening,
+            rain_elevation: self.rain_elevation,
+            rain_windward: self.rain_windward,
+            rain_wind_direction: self.rain_wind_direction,
+            flow_memory: self.flow_memory,
+            shallow_water: ShallowWaterParams {
+                steps: self.water_steps,
+                time_step: self.water_time_step,
+                rain_rate: self.water_rain_rate,
+                gravity: self.water_gravity,
+                sediment_capacity: self.water_sediment_capacity,
+                dissolving: self.water_dissolving,
+                deposition: self.water_deposition,
+                evaporation: self.water_evaporation,
+                min_tilt: self.water_min_tilt,
+                max_erosion_depth: self.water_max_erosion_depth,
+            },
+            layers: self.layers.clone(),
+        }
+    }
+
+    /// Sets the exported parameters from an `ErosionParams`.
+    fn set_params(&mut self, params: &ErosionParams) {
+        self.model = params.model.to_string().into();
+        self.gravity = params.gravity;
+        self.capacity = params.capacity;
+        self.inertia = params.inertia;
+        self.erosion_factor = params.erosion_factor;
+        self.deposition_factor = params.deposition_factor;
+        self.diameter = params.diameter;
+        self.lifetime = params.lifetime;
+        self.starting_mass = params.starting_mass;
+        self.evaporation = params.evaporation;
+        self.min_velocity = params.min_velocity;
+        self.boundary = params.boundary.to_string().into();
+        self.drop_count = params.drop_count as u32;
+        self.tiled = params.tiled;
+        self.pyramid_levels = params.pyramid_levels;
+        self.pyramid_iterations = params.pyramid_iterations;
+        self.thermal_talus = params.thermal_talus;
+        self.thermal_rate = params.thermal_rate;
+        self.thermal_interval = params.thermal_interval;
+        self.hardening = params.hardening;
+        self.rain_elevation = params.rain_elevation;
+        self.rain_windward = params.rain_windward;
+        self.rain_wind_direction = params.rain_wind_direction;
+        self.flow_memory = params.flow_memory;
+        self.water_steps = params.shallow_water.steps;
+        self.water_time_step = params.shallow_water.time_step;
+        self.water_rain_rate = params.shallow_water.rain_rate;
+        self.water_gravity = params.shallow_water.gravity;
+        self.water_sediment_capacity = params.shallow_water.sediment_capacity;
+        self.water_dissolving = params.shallow_water.dissolving;
+        self.water_deposition = params.shallow_water.deposition;
+        self.water_evaporation = params.shallow_water.evaporation;
+        self.water_min_tilt = params.shallow_water.min_tilt;
+        self.water_max_erosion_depth = params.shallow_water.max_erosion_depth;
+        // New layers mean new strata, which the undo history wasn't recorded against
+        if self.layers != params.layers {
+            HISTORY.lock().unwrap().clear();
+        }
+        self.layers = params.layers.clone();
+    }
+}
+
+/// A heightmap read by `read_heightmap`.
+struct LoadedHeightmap {
+    heightmap: Vec<f32>,
+    // The green channel, if it was asked for as the rock hardness
+    hardness: Option<Vec<f32>>,
+    dims: (usize, usize),
+}
+
+/// Reads a heightmap and rescales its heights, and reads its green channel as the rock
+/// hardness if `hardness_from_green`.
+///
+/// Files on disk are read with `io`, falling back to Godot's imported textures for `res://`
+/// files that only exist inside an exported game's pack. Only EXRs and imported textures
+/// have a green channel - other files are loaded without hardness, with a warning. Errors
+/// are reported to Godot.
+fn read_heightmap(
+    path: &GString,
+    hardness_from_green: bool,
+    scale: io::HeightScale,
+) -> Option<LoadedHeightmap> {
+    let global = globalize_path(path);
+    if !Path::new(&global).exists() {
+        let mut loaded = load_texture(path, hardness_from_green)?;
+        scale.apply(&mut loaded.heightmap);
+        return Some(loaded);
+    }
+
+    let (mut heightmap, dims) = match io::read_heightmap(&global, None) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            godot_error!("Failed to load a heightmap from {path}: {e}");
+            return None;
+        }
+    };
+    scale.apply(&mut heightmap);
+
+    let hardness = if hardness_from_green && !io::is_exr(&global) {
+        godot_warn!("{path} has no green channel, so it's loaded without a hardness map");
+        None
+    } else if hardness_from_green {
+        match io::read_exr_channel(&global, "G") {
+            Ok((hardness, _)) => Some(hardness),
+            Err(e) => {
+                godot_error!("Failed to read the hardness from the green channel of {path}: {e}");
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    Some(LoadedHeightmap {
+        heightmap,
+        hardness,
+        dims,
+    })
+}
+
+/// Reads a heightmap from the red channel of an imported texture, and the rock hardness from
+/// its green channel if `hardness_from_green`.
+fn load_texture(path: &GString, hardness_from_green: bool) -> Option<LoadedHeightmap> {
+    let Some(mut image) = ResourceLoader::singleton()
+        .load(path)
+        .and_then(|resource| resource.try_cast::<Texture2D>().ok())
+        .and_then(|texture| texture.get_image())
+    else {
+        godot_error!("Failed to load a heightmap from {path}");
+        return None;
+    };
+
+    let dims = (image.get_width() as usize, image.get_height() as usize);
+    image.convert(Format::RGF);
+
+    let (heightmap, green): (Vec<f32>, Vec<f32>) = image
+        .get_data()
+        .as_slice()
+        .chunks_exact(8)
+        .map(|bytes| {
+            (
+                f32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                f32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            )
+        })
+        .unzip();
+
+    Some(LoadedHeightmap {
+        heightmap,
+        hardness: hardness_from_green.then_some(green),
+        dims,
+    })
+}
+
+/// Loads the red channel of an image the same size as the terrain, like a hardness map.
+/// Errors are reported using `what` to describe the map.
+fn load_map(path: &GString, what: &str) -> Option<Vec<f32>> {
+    let Some(image) = ResourceLoader::singleton()
+        .load(path)
+        .and_then(|resource| resource.try_cast::<Texture2D>().ok())
+        .and_then(|texture| texture.get_image())
+    else {
+        godot_error!("Failed to load a {what} from {path}");
+        return None;
+    };
+
+    let dims = *DIMS.read().unwrap();
+    if (image.get_width() as usize, image.get_height() as usize) != dims {
+        godot_error!("The {what} doesn't match the terrain's size of {dims:?}");
+        return None;
+    }
+
+    Some(image_to_floats(image))
+}
+
+/// Creates a single-channel float texture from a map, like the erosion.
+fn float_texture(map: &[f32], dims: (i32, i32)) -> Gd<ImageTexture> {
+    let mut array = PackedByteArray::new();
+    array.extend(map.iter().flat_map(|&x| x.to_le_bytes()));
+
+    let image = Image::create_from_data(dims.0, dims.1, false, Format::RF, &array).unwrap();
+
+    ImageTexture::create_from_image(&image).unwrap()
+}
+
+/// Creates an RGB float image from normal map colours like those from
+/// `SurfaceMaps::normal_colours`.
+fn normal_image(colours: &[[f32; 3]], dims: (i32, i32)) -> Gd<Image> {
+    let mut array = PackedByteArray::new();
+    array.extend(colours.iter().flatten().flat_map(|&x| x.to_le_bytes()));
+
+    Image::create_from_data(dims.0, dims.1, false, Format::RGBF, &array).unwrap()
+}
+
+/// Reads the red channel of an image as floats.
+fn image_to_floats(mut image: Gd<Image>) -> Vec<f32> {
+    image.convert(Format::RF);
+
+    image
+        .get_data()
+        .as_slice()
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+/// Turns `res://` and `user://` paths into ones the filesystem understands.
+fn globalize_path(path: &GString) -> String {
+    ProjectSettings::singleton()
+        .globalize_path(path)
+        .to_string()
+}
+
+/// The path of a map written next to the heightmap at `output`, with `_name` added to its
+/// file name - `output_flow.exr` for `output.exr`.
+fn sibling_path(output: &str, name: &str) -> String {
+    let output = Path::new(output);
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+
+    output
+        .with_file_name(format!("{stem}_{name}.exr"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Copies of the maps the undo history covers - the heightmap, the hardness and the
+/// thickness of the material layers, in the order `TerrainMesh::step_history` gives them.
+fn history_maps(simulator: &ErosionSimulator) -> [Vec<f32>; 3] {
+    [
+        simulator.heightmap.clone(),
+        simulator
+            .hardness()
+            .map(<[f32]>::to_vec)
+            .unwrap_or_default(),
+        simulator
+            .strata()
+            .map(|strata| strata.packed_thickness().to_vec())
+            .unwrap_or_default(),
+    ]
+}
+
+/// Records what the last iteration changed since `snapshot` in the undo history, bringing
+/// `snapshot` up to date in place.
+fn record_pass(snapshot: &mut [Vec<f32>; 3], simulator: &ErosionSimulator) {
+    let [heightmap, hardness, strata] = snapshot;
+    let pass = Pass::advance(&mut [
+        (heightmap, &simulator.heightmap),
+        (hardness, simulator.hardness().unwrap_or_default()),
+        (
+            strata,
+            simulator
+                .strata()
+                .map(Strata::packed_thickness)
+                .unwrap_or_default(),
+        ),
+    ]);
+
+    // Only lock the history once the slow part is done
+    HISTORY.lock().unwrap().record(pass);
+}
+
+/// Bakes the normal, slope and curvature maps of `heightmap` and updates their textures.
+fn update_surface_maps(
+    heightmap: &[f32],
+    dims: (usize, usize),
+    strength: f32,
+    wrap: bool,
+    rs: &mut Gd<RenderingServer>,
+) {
+    let size = (dims.0 as i32, dims.1 as i32);
+    let maps = SurfaceMaps::bake(heightmap, dims, strength, wrap);
+
+    let normals = normal_image(&maps.normal_colours(), size);
+    rs.texture_2d_update(*NORMAL_ID.read().unwrap(), &normals, 0);
+    update_texture(&maps.slope, size, *SLOPE_ID.read().unwrap(), rs);
+    update_texture(&maps.curvature, size, *CURVATURE_ID.read().unwrap(), rs);
 }
 
 /// Updates the texture with the new height data
@@ -0,0 +1,125 @@
+use rayon::prelude::*;
+
+/// Offsets to the 8 neighbours of a cell, with the distance to each.
+const NEIGHBOURS: [(isize, isize, f32); 8] = [
+    (-1, -1, std::f32::consts::SQRT_2),
+    (0, -1, 1.0),
+    (1, -1, std::f32::consts::SQRT_2),
+    (-1, 0, 1.0),
+    (1, 0, 1.0),
+    (-1, 1, std::f32::consts::SQRT_2),
+    (0, 1, 1.0),
+    (1, 1, std::f32::consts::SQRT_2),
+];
+
+/// Run a single thermal weathering (talus) pass over the heightmap.
+///
+/// # Arguments
+///
+/// * `heightmap` - The heightmap to weather, stored row by row.
+/// * `dims` - The dimensions of the heightmap as a tuple of `(x: usize, y: usize)`.
+/// * `talus` - The steepest stable slope, as the height difference per cell of distance.
+/// * `rate` - The fraction of the excess material moved by this pass, from `0.0` to `1.0`.
+/// * `wrap` - Whether the heightmap repeats, so cells on opposite edges are neighbours.
+///
+/// # Returns
+///
+/// The total amount of material moved.
+///
+/// # Explanation
+///
+/// Any cell steeper than `talus` towards one of its 8 neighbours sheds material
+/// downhill. The amount shed is `rate` times half the largest height difference
+/// above the talus - half, so a pair of cells at most levels out rather than
+/// swapping over. That material is split between every neighbour the cell is too
+/// steep towards, in proportion to how far over the talus each slope is.
+///
+/// Every cell reads from the heightmap as it was at the start of the pass, so
+/// the result doesn't depend on the order cells are visited in and rows can be
+/// processed in parallel.
+pub fn thermal_erode(
+    heightmap: &mut [f32],
+    dims: (usize, usize),
+    talus: f32,
+    rate: f32,
+    wrap: bool,
+) -> f32 {
+    let snapshot: &[f32] = heightmap;
+
+    // Work out how much each cell sheds, and the total excess it's split by
+    let shed: Vec<(f32, f32)> = (0..snapshot.len())
+        .into_par_iter()
+        .map(|index| {
+            let mut max_excess = 0.0f32;
+            let mut excess_sum = 0.0;
+
+            for (x, y, distance) in neighbours(index, dims, wrap) {
+                let excess = slope_excess(snapshot, index, y * dims.0 + x, distance, talus);
+                max_excess = max_excess.max(excess * distance);
+                excess_sum += excess;
+            }
+
+            (max_excess * rate / 2.0, excess_sum)
+        })
+        .collect();
+
+    // Every cell loses what it sheds and gains its share of what its neighbours shed
+    let changes: Vec<f32> = (0..snapshot.len())
+        .into_par_iter()
+        .map(|index| {
+            let mut change = -shed[index].0;
+
+            for (x, y, distance) in neighbours(index, dims, wrap) {
+                let neighbour = y * dims.0 + x;
+                let (amount, excess_sum) = shed[neighbour];
+                if amount <= 0.0 {
+                    continue;
+                }
+
+                let excess = slope_excess(snapshot, neighbour, index, distance, talus);
+                change += amount * excess / excess_sum;
+            }
+
+            change
+        })
+        .collect();
+
+    let mut moved = 0.0;
+    for (height, change) in heightmap.iter_mut().zip(changes) {
+        *height += change;
+        moved += change.max(0.0);
+    }
+
+    moved
+}
+
+/// Iterate over the in-bounds neighbours of a cell as `(x, y, distance)`, wrapping
+/// around the edges if `wrap` is set.
+fn neighbours(
+    index: usize,
+    dims: (usize, usize),
+    wrap: bool,
+) -> impl Iterator<Item = (usize, usize, f32)> {
+    let x = (index % dims.0) as isize;
+    let y = (index / dims.0) as isize;
+
+    NEIGHBOURS.iter().filter_map(move |&(dx, dy, distance)| {
+        let (mut nx, mut ny) = (x + dx, y + dy);
+        if wrap {
+            nx = nx.rem_euclid(dims.0 as isize);
+            ny = ny.rem_euclid(dims.1 as isize);
+        }
+        if nx < 0 || ny < 0 || nx >= dims.0 as isize || ny >= dims.1 as isize {
+            return None;
+        }
+
+        Some((nx as usize, ny as usize, distance))
+    })
+}
+
+/// How far the slope from `from` down to `to` is over the talus, or `0.0` if it isn't.
+fn slope_excess(heightmap: &[f32], from: usize, to: usize, distance: f32, talus: f32) -> f32 {
+    let slope = (heightmap[from] - heightmap[to]) / distance;
+
+    (slope - talus).max(0.0)
+}
@@ -0,0 +1,198 @@
+/// Splits a map into square tiles that can be eroded in parallel without any two
+/// `Raindrop`s touching the same cell at the same time.
+///
+/// # Explanation
+///
+/// Every tile gets a colour from whether its column and row are odd or even, so
+/// tiles of the same colour always have a tile of another colour between them.
+/// Each tile's `Raindrop`s are simulated on a window covering the tile plus a
+/// halo around it, which is at most half a tile wide - so the windows of tiles
+/// with the same colour never overlap, and all of them can be eroded at once.
+/// The four colours are then run one after another.
+///
+/// The halo is made wide enough that a `Raindrop` can't get out of its window
+/// within its lifetime, so no drop is cut short by the tiling.
+///
+/// On a map that wraps around, windows wrap around too. Tiles of the same colour
+/// need a tile between them all the way round, so there has to be an even number
+/// of whole tiles across and down - the last tile on each side stretches to cover
+/// what's left over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tiling {
+    // The dimensions of the map as a tuple of `(x: usize, y: usize)`
+    dims: (usize, usize),
+    // The width and height of a tile
+    tile_size: usize,
+    // How far the window reaches past each side of its tile
+    halo: usize,
+    // The number of tiles across and down
+    tiles: (usize, usize),
+    // Whether the map wraps around its edges, so windows do too
+    wrap: bool,
+}
+
+/// The part of the map a tile's `Raindrop`s are simulated on - the tile plus its
+/// halo, clipped to the map or wrapped around it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// The position of the window's top left cell on the map.
+    pub origin: (usize, usize),
+    /// The dimensions of the window as a tuple of `(x: usize, y: usize)`.
+    pub dims: (usize, usize),
+}
+
+impl Tiling {
+    /// Work out the tiling for a map with dimensions `dims`, for `Raindrop`s that
+    /// travel at most `reach` cells from where they start - including the brush
+    /// they erode with. If `wrap` is set the map repeats past its edges.
+    pub fn new(dims: (usize, usize), reach: usize, wrap: bool) -> Self {
+        // The extra cell covers the drop reading the heights to its right and below
+        let halo = reach + 1;
+        let tile_size = halo * 2;
+
+        let tiles = if wrap {
+            (dims.0 / tile_size / 2 * 2, dims.1 / tile_size / 2 * 2)
+        } else {
+            (dims.0.div_ceil(tile_size), dims.1.div_ceil(tile_size))
+        };
+
+        Tiling {
+            dims,
+            tile_size,
+            halo,
+            tiles,
+            wrap,
+        }
+    }
+
+    /// The width and height of a tile.
+    pub fn tile_size(&self) -> usize {
+        self.tile_size
+    }
+
+    /// The number of tiles.
+    pub fn len(&self) -> usize {
+        self.tiles.0 * self.tiles.1
+    }
+
+    /// Whether there are no tiles, which happens for an empty map - or a wrapping one
+    /// too small for two tiles across and down.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The index of the tile containing the cell at `(x, y)`.
+    pub fn tile_at(&self, x: usize, y: usize) -> usize {
+        let column = (x / self.tile_size).min(self.tiles.0 - 1);
+        let row = (y / self.tile_size).min(self.tiles.1 - 1);
+
+        row * self.tiles.0 + column
+    }
+
+    /// The colour of a tile, from `0` to `3`.
+    pub fn colour(&self, tile: usize) -> usize {
+        let (column, row) = (tile % self.tiles.0, tile / self.tiles.0);
+
+        (row % 2) * 2 + column % 2
+    }
+
+    /// The window a tile's `Raindrop`s are simulated on.
+    pub fn window(&self, tile: usize) -> Window {
+        let (column, row) = (tile % self.tiles.0, tile / self.tiles.0);
+        let start = (column * self.tile_size, row * self.tile_size);
+
+        // The last tile on each side reaches to the edge of the map
+        let end = (
+            if column + 1 == self.tiles.0 {
+                self.dims.0
+            } else {
+                start.0 + self.tile_size
+            },
+            if row + 1 == self.tiles.1 {
+                self.dims.1
+            } else {
+                start.1 + self.tile_size
+            },
+        );
+
+        if self.wrap {
+            return Window {
+                origin: (
+                    (start.0 + self.dims.0 - self.halo) % self.dims.0,
+                    (start.1 + self.dims.1 - self.halo) % self.dims.1,
+                ),
+                dims: (
+                    end.0 - start.0 + self.halo * 2,
+                    end.1 - start.1 + self.halo * 2,
+                ),
+            };
+        }
+
+        let origin = (
+            start.0.saturating_sub(self.halo),
+            start.1.saturating_sub(self.halo),
+        );
+        let end = (
+            (end.0 + self.halo).min(self.dims.0),
+            (end.1 + self.halo).min(self.dims.1),
+        );
+
+        Window {
+            origin,
+            dims: (end.0 - origin.0, end.1 - origin.1),
+        }
+    }
+}
+
+impl Window {
+    /// The number of cells in the window.
+    pub fn cells(&self) -> usize {
+        self.dims.0 * self.dims.1
+    }
+
+    /// The index on a map with dimensions `map_dims` of the window's cell at `index`.
+    pub fn to_map(&self, index: usize, map_dims: (usize, usize)) -> usize {
+        let (x, y) = (index % self.dims.0, index / self.dims.0);
+
+        (self.origin.1 + y) % map_dims.1 * map_dims.0 + (self.origin.0 + x) % map_dims.0
+    }
+
+    /// Copy the window out of a map with dimensions `map_dims`.
+    pub fn copy_from(&self, map: &[f32], map_dims: (usize, usize)) -> Vec<f32> {
+        let mut window = Vec::with_capacity(self.cells());
+        for (start, _, len) in self.spans(map_dims) {
+            window.extend_from_slice(&map[start..start + len]);
+        }
+
+        window
+    }
+
+    /// Copy the window back into a map with dimensions `map_dims`.
+    pub fn copy_to(&self, window: &[f32], map: &mut [f32], map_dims: (usize, usize)) {
+        for (start, offset, len) in self.spans(map_dims) {
+            map[start..start + len].copy_from_slice(&window[offset..offset + len]);
+        }
+    }
+
+    /// The runs of consecutive map cells the window covers, top to bottom, as
+    /// `(map index, window index, length)` - one per row, or two if the row wraps
+    /// around the right edge of a map with dimensions `map_dims`.
+    pub fn spans(
+        &self,
+        map_dims: (usize, usize),
+    ) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let first = self.dims.0.min(map_dims.0 - self.origin.0);
+
+        (0..self.dims.1).flat_map(move |y| {
+            let row = (self.origin.1 + y) % map_dims.1 * map_dims.0;
+            let offset = y * self.dims.0;
+
+            [
+                (row + self.origin.0, offset, first),
+                (row, offset + first, self.dims.0 - first),
+            ]
+            .into_iter()
+            .filter(|&(_, _, len)| len > 0)
+        })
+    }
+}